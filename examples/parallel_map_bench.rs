@@ -0,0 +1,27 @@
+//! Throughput of `parallel_map` for a cheap op, by number of workers.
+//!
+//! Run with `cargo run --release --example parallel_map_bench`.
+
+use std::time::Instant;
+
+use rust_experiments::parallel::parallel_map;
+use tokio::stream::StreamExt as _;
+
+const ITEMS: usize = 1_000_000;
+
+#[tokio::main(core_threads = 8)]
+async fn main() {
+    for &n in &[1, 2, 4, 8, 16, 32] {
+        let started = Instant::now();
+        let sum = parallel_map(0..ITEMS, |i| i * i, n)
+            .fold(0, |sum: usize, i| sum.wrapping_add(i))
+            .await;
+        let elapsed = started.elapsed().as_secs_f64();
+        println!(
+            "n = {:>2}: {:>10.0} items/s (checksum {})",
+            n,
+            ITEMS as f64 / elapsed,
+            sum
+        );
+    }
+}
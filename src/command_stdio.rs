@@ -1,6 +1,4 @@
-use std::io::Write as _;
-use std::process::{Command, Stdio};
-
+use crate::process::{Input, Output, ProcessSpec};
 use crate::Result;
 
 static INPUT: &str = r#"
@@ -17,15 +15,15 @@ sleep 1
 echo hello
 "#;
 
-pub fn run() -> Result<()> {
-    let mut child = Command::new("bash")
+#[tokio::main]
+pub async fn run() -> Result<()> {
+    let outcome = ProcessSpec::new("bash")
         .args(&["-c", SCRIPT])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-    let stdin = child.stdin.as_mut().unwrap();
-    stdin.write_all(INPUT.as_bytes())?;
-    eprintln!("{:?}", child.wait_with_output()?);
+        .stdin(Input::Bytes(INPUT.into()))
+        .stderr(Output::Inherit)
+        .run()
+        .await?;
+    eprintln!("{:?}", outcome);
 
     Ok(())
 }
@@ -4,7 +4,8 @@
 // mod command_timeout;
 // mod hyper_client;
 // mod oauth;
-mod parallel;
+pub mod parallel;
+pub mod process;
 
 pub type Result<T> = anyhow::Result<T>;
 pub type Error = anyhow::Error;
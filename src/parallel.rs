@@ -1,87 +1,766 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::future::Future;
 use std::iter::Iterator;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::vec;
 
-use futures::future::{self, TryJoinAll};
+use futures::future::{
+    self, AbortHandle, Abortable, Aborted, BoxFuture, FutureExt as _, TryJoinAll,
+};
+use futures::ready;
+use futures::task::AtomicWaker;
 use rayon::iter::ParallelIterator as _;
 use rayon::prelude::*;
 use tokio;
-use tokio::stream::{Stream, StreamExt as _};
-use tokio::sync::mpsc::{self, error::SendError, Receiver};
-use tokio::sync::Mutex;
-use tokio::task::{JoinError, JoinHandle};
+use tokio::stream::{self, Stream, StreamExt as _};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::{oneshot, Mutex, Semaphore};
 
-struct ParallelIterator<T> {
-    rx: Receiver<T>,
-    _handle: TryJoinAll<JoinHandle<Result<(), SendError<T>>>>,
+mod adaptive;
+mod batch;
+mod config;
+mod executor;
+mod ext;
+mod journal;
+mod keyed;
+mod pipeline;
+mod priority;
+mod progress;
+mod promise;
+mod rate_limit;
+mod retry;
+mod scope;
+mod time_limit;
+
+pub use self::adaptive::{parallel_map_adaptive, AdaptiveLimit};
+pub use self::batch::{batched, Batched};
+pub use self::config::ParallelConfig;
+pub use self::executor::{Executor, RayonExecutor, TokioExecutor};
+pub use self::ext::{ParallelIteratorExt, ParallelStreamExt};
+pub use self::journal::{
+    parallel_map_journaled, parallel_map_journaled_async, try_parallel_map_journaled_async,
+};
+pub use self::keyed::{parallel_map_keyed, parallel_map_keyed_async};
+pub use self::pipeline::Pipeline;
+pub use self::priority::{parallel_map_prioritized, parallel_map_prioritized_async};
+pub use self::progress::{Progress, ProgressSnapshot};
+pub use self::promise::{CancellationToken, Promise, PromiseError};
+pub use self::rate_limit::RateLimiter;
+pub use self::retry::{Retried, RetryPolicy};
+pub use self::scope::{parallel_map_scoped, scope, Scope, ScopedHandle};
+pub use self::time_limit::{parallel_map_timeout, TimeLimitError, TimeLimits};
+
+use self::config::Budget;
+use self::executor::{JoinError, JoinHandle};
+
+pub struct ParallelIterator<T> {
+    /// Workers send their results in batches, see `MAX_BATCH_DELAY`.
+    rx: Receiver<Vec<T>>,
+    batch: vec::IntoIter<T>,
+    workers: Workers,
+    progress: Progress,
+    /// Given back as results are yielded. Maps without one don't limit their buffer beyond the
+    /// channel capacity.
+    budget: Option<Arc<Budget<T>>>,
+    /// Results held back by the workers. Maps without them send every result right away.
+    held: Option<Arc<Held<T>>>,
+}
+
+impl<T> ParallelIterator<T> {
+    fn new(
+        rx: Receiver<Vec<T>>,
+        workers: Workers,
+        progress: Progress,
+        budget: Option<Arc<Budget<T>>>,
+        held: Option<Arc<Held<T>>>,
+    ) -> Self {
+        ParallelIterator {
+            rx,
+            batch: Vec::new().into_iter(),
+            workers,
+            progress,
+            budget,
+            held,
+        }
+    }
 }
 
+// None of the fields are pinned.
+impl<T> Unpin for ParallelIterator<T> {}
+
 impl<T> Stream for ParallelIterator<T> {
     type Item = T;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        Receiver::<T>::poll_next(Pin::new(&mut self.rx), cx)
+        self.workers.poll_panic(cx);
+        loop {
+            if let Some(item) = self.batch.next() {
+                if let Some(budget) = &self.budget {
+                    budget.release(budget.weigh(&item));
+                }
+                return Poll::Ready(Some(item));
+            }
+            let batch = match Pin::new(&mut self.rx).poll_next(cx) {
+                Poll::Ready(Some(batch)) => batch,
+                Poll::Ready(None) => return Poll::Ready(None),
+                // Rather than waiting for the workers to send what they hold back.
+                Poll::Pending => match self.held.as_ref().and_then(|held| held.take_all(cx)) {
+                    Some(batch) => batch,
+                    None => return Poll::Pending,
+                },
+            };
+            if let Some(held) = &self.held {
+                held.waiting.store(false, Ordering::SeqCst);
+            }
+            self.batch = batch.into_iter();
+        }
+    }
+}
+
+/// Results that workers hold back to send them in batches, see `MAX_BATCH_DELAY`.
+struct Held<T> {
+    /// One per worker.
+    outboxes: Vec<SyncMutex<Vec<T>>>,
+    /// Set while the consumer has run out of results, so that the next result wakes it.
+    waiting: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl<T> Held<T> {
+    fn new(n: usize) -> Self {
+        Held {
+            outboxes: (0..n).map(|_| SyncMutex::new(Vec::new())).collect(),
+            waiting: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn push(&self, worker: usize, result: T) {
+        self.outboxes[worker].lock().unwrap().push(result);
+        if self.waiting.swap(false, Ordering::SeqCst) {
+            self.waker.wake();
+        }
+    }
+
+    /// Sends the results held back by `worker`, if any. Returns whether the consumer is still
+    /// there.
+    async fn send(&self, worker: usize, tx: &mut Sender<Vec<T>>) -> bool {
+        let results = mem::take(&mut *self.outboxes[worker].lock().unwrap());
+        results.is_empty() || tx.send(results).await.is_ok()
+    }
+
+    /// Takes the results of all workers, so that none of them waits for the op its worker runs
+    /// next. The consumer is marked as waiting before the outboxes are locked, so a result pushed
+    /// meanwhile is either taken here or wakes the consumer.
+    fn take_all(&self, cx: &mut Context) -> Option<Vec<T>> {
+        self.waker.register(cx.waker());
+        self.waiting.store(true, Ordering::SeqCst);
+        let mut results = Vec::new();
+        for outbox in &self.outboxes {
+            results.append(&mut outbox.lock().unwrap());
+        }
+        if results.is_empty() {
+            None
+        } else {
+            Some(results)
+        }
+    }
+}
+
+impl<T> ParallelIterator<T> {
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// Stops workers from pulling new items. Items already in flight are still yielded, but items
+    /// that workers took from the input in a chunk and haven't started yet are dropped.
+    pub fn cancel(&self) {
+        self.workers.cancel();
+    }
+
+    /// Cancels the workers and waits for the items already in flight. Like `cancel`, drops the
+    /// items that were taken but not started.
+    pub async fn shutdown(self) -> Vec<T> {
+        self.cancel();
+        self.collect().await
+    }
+}
+
+/// Dropping the workers aborts them right away, so a consumer can stop early for free.
+struct Workers {
+    handle: Option<TryJoinAll<JoinHandle<Result<(), Aborted>>>>,
+    aborts: Vec<AbortHandle>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Workers {
+    fn spawn<I, F>(executor: &dyn Executor, workers: I, stop: Arc<AtomicBool>) -> Self
+    where
+        I: IntoIterator<Item = F>,
+        F: 'static + Future<Output = ()> + Send,
+    {
+        let mut aborts = Vec::new();
+        let tasks = workers.into_iter().map(|worker| {
+            let (abort, registration) = AbortHandle::new_pair();
+            aborts.push(abort);
+            executor::spawn(executor, Abortable::new(worker, registration))
+        });
+        let handle = future::try_join_all(tasks);
+        Workers {
+            handle: Some(handle),
+            aborts,
+            stop,
+        }
+    }
+
+    fn cancel(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// Aborts all workers, dropping the items they are working on.
+    fn abort(&self) {
+        self.cancel();
+        for abort in &self.aborts {
+            abort.abort();
+        }
+    }
+
+    /// Aborts the remaining workers and resumes the panic as soon as one of the workers panics.
+    fn poll_panic(&mut self, cx: &mut Context) {
+        let handle = match &mut self.handle {
+            Some(handle) => handle,
+            None => return,
+        };
+        let result = match Pin::new(handle).poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return,
+        };
+        self.handle = None;
+        if let Err(err) = result {
+            self.abort();
+            if let JoinError::Panicked(payload) = err {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+pub struct TryParallelIterator<T, E> {
+    inner: ParallelIterator<Result<T, E>>,
+    done: bool,
+}
+
+impl<T, E> TryParallelIterator<T, E> {
+    pub fn progress(&self) -> Progress {
+        self.inner.progress()
+    }
+
+    /// Stops workers from pulling new items. Items already in flight are still yielded, but items
+    /// that workers took from the input in a chunk and haven't started yet are dropped.
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    /// Cancels the workers and waits for the items already in flight. Like `cancel`, drops the
+    /// items that were taken but not started.
+    pub async fn shutdown(self) -> Vec<Result<T, E>> {
+        self.cancel();
+        self.collect().await
+    }
+}
+
+impl<T, E> Stream for TryParallelIterator<T, E> {
+    type Item = Result<T, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let item = ready!(Pin::new(&mut self.inner).poll_next(cx));
+        if let Some(Ok(_)) = item {
+            return Poll::Ready(item);
+        }
+        // Either the first error or the end of the stream, so nothing will be yielded after this.
+        self.done = true;
+        self.inner.workers.abort();
+        Poll::Ready(item)
+    }
+}
+
+/// Maps `iter` with `op` on the workers of `config`, yielding results as they finish.
+///
+/// `config` may be a plain `usize` for the number of workers.
+pub fn parallel_map<I, F, T, U>(
+    iter: I,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> ParallelIterator<U>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> U,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    spawn_workers(
+        stream::iter(iter),
+        move |item| future::ready(op(item)),
+        WorkerConfig::new(config.into(), config::itself),
+    )
+}
+
+/// Same as `parallel_map`, but `op` returns a future, so workers don't block the runtime while
+/// waiting for I/O. At most one future per worker is in flight at a time.
+pub fn parallel_map_async<I, F, Fut, T, U>(
+    iter: I,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> ParallelIterator<U>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = U> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    parallel_map_stream(stream::iter(iter), op, config)
+}
+
+/// Same as `parallel_map_async`, but pulls items from a stream, so that the input itself can be
+/// produced asynchronously.
+pub fn parallel_map_stream<S, F, Fut, T, U>(
+    stream: S,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> ParallelIterator<U>
+where
+    S: 'static + Stream<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = U> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    spawn_workers(stream, op, WorkerConfig::new(config.into(), config::itself))
+}
+
+/// Same as `parallel_map`, but for a fallible `op`.
+///
+/// After the first error, workers stop pulling new items, in-flight items are dropped and the
+/// error is the last item yielded. Errors count against the buffer of `config`, but only
+/// successes are weighed.
+pub fn try_parallel_map<I, F, T, U, E>(
+    iter: I,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> TryParallelIterator<U, E>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Result<U, E>,
+    T: 'static + Send,
+    U: 'static + Send,
+    E: 'static + Send,
+{
+    try_parallel_map_async(iter, move |item| future::ready(op(item)), config)
+}
+
+/// Same as `try_parallel_map`, but `op` returns a future.
+pub fn try_parallel_map_async<I, F, Fut, T, U, E>(
+    iter: I,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> TryParallelIterator<U, E>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = Result<U, E>> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+    E: 'static + Send,
+{
+    try_parallel_map_stream(stream::iter(iter), op, config)
+}
+
+/// Same as `try_parallel_map_async`, but pulls items from a stream.
+pub fn try_parallel_map_stream<S, F, Fut, T, U, E>(
+    stream: S,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> TryParallelIterator<U, E>
+where
+    S: 'static + Stream<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = Result<U, E>> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+    E: 'static + Send,
+{
+    let config = WorkerConfig {
+        is_failure: Result::is_err,
+        stop_on_failure: true,
+        ..WorkerConfig::new(config.into(), config::ok)
+    };
+    TryParallelIterator {
+        inner: spawn_workers(stream, op, config),
+        done: false,
     }
 }
 
-fn parallel_map<I, F, T, U>(iter: I, op: F, n: usize) -> ParallelIterator<U>
+/// Same as `parallel_map`, but runs `op` on `pool` instead of on the async worker threads, so
+/// that a CPU-heavy map doesn't starve other tasks on the runtime.
+pub fn parallel_map_blocking<I, F, T, U>(
+    iter: I,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+    pool: BlockingPool,
+) -> ParallelIterator<U>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> U,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    parallel_map_async(
+        iter,
+        move |item| {
+            let op = op.clone();
+            pool.run(move || op(item))
+        },
+        config,
+    )
+}
+
+/// Same as `parallel_map`, but yields results in the order of `iter`.
+///
+/// At most two items per worker are pulled ahead of the next result to be yielded, so the
+/// reorder buffer stays bounded even if one item is much slower than the others. Results waiting
+/// for their turn don't count against the buffer of `config`.
+pub fn parallel_map_ordered<I, F, T, U>(
+    iter: I,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> OrderedParallelIterator<U>
 where
     I: 'static + Iterator<Item = T> + Send,
     F: 'static + Send + Sync + Clone + Fn(T) -> U,
     T: 'static + Send,
     U: 'static + Send,
 {
-    let (tx, rx) = mpsc::channel::<U>(n);
-    let iter = Arc::new(Mutex::new(iter));
-    let tasks = (0..n).map(|_| {
-        let iter = Arc::clone(&iter);
+    let config = config.into();
+    let workers = config.workers();
+    let window = Arc::new(Semaphore::new(2 * workers));
+    let config = WorkerConfig {
+        window: Some(Arc::clone(&window)),
+        ..WorkerConfig::new(config, config::second)
+    };
+    let inner = spawn_workers(
+        stream::iter(iter.enumerate()),
+        move |(i, item)| future::ready((i, op(item))),
+        config,
+    );
+    OrderedParallelIterator {
+        inner,
+        pending: BTreeMap::new(),
+        next: 0,
+        window,
+        workers,
+    }
+}
+
+/// Settings of `spawn_workers` that differ between the public maps.
+struct WorkerConfig<U> {
+    workers: usize,
+    executor: Arc<dyn Executor>,
+    budget: Arc<Budget<U>>,
+    /// Limits how far workers may run ahead of the consumer. Permits are taken before pulling an
+    /// item and must be given back by the consumer.
+    window: Option<Arc<Semaphore>>,
+    /// Tells the progress counters which results are failures.
+    is_failure: fn(&U) -> bool,
+    /// Whether workers stop pulling new items after the first failure.
+    stop_on_failure: bool,
+    /// Set by `cancel`, and after the first failure if `stop_on_failure` is set.
+    stop: Arc<AtomicBool>,
+    /// Whether workers still run the rest of their chunk after a stop instead of dropping it, for
+    /// an `op` that watches `stop` and turns the items it shouldn't start into results.
+    finish_chunk: bool,
+    /// Upper bound of the chunks taken by `pull_chunk`. A worker runs its chunk in order, so
+    /// maps that care which worker gets which item take one item at a time.
+    max_chunk: usize,
+}
+
+impl<U> WorkerConfig<U> {
+    /// `project` picks the part of a result that `config` weighs.
+    fn new<V: 'static>(config: ParallelConfig<V>, project: fn(&U) -> Option<&V>) -> Self
+    where
+        U: 'static,
+    {
+        WorkerConfig {
+            workers: config.workers(),
+            executor: Arc::clone(config.executor_ref()),
+            budget: config.budget(project),
+            window: None,
+            is_failure: |_| false,
+            stop_on_failure: false,
+            stop: Arc::new(AtomicBool::new(false)),
+            finish_chunk: false,
+            max_chunk: MAX_CHUNK,
+        }
+    }
+}
+
+fn spawn_workers<S, F, Fut, T, U>(stream: S, op: F, config: WorkerConfig<U>) -> ParallelIterator<U>
+where
+    S: 'static + Stream<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = U> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    let WorkerConfig {
+        workers: n,
+        executor,
+        budget,
+        window,
+        is_failure,
+        stop_on_failure,
+        stop,
+        finish_chunk,
+        max_chunk,
+    } = config;
+    // Every batch holds at least one result of the budget, so the budget fills up first.
+    let (tx, rx) = mpsc::channel::<Vec<U>>(budget.max_results());
+    let progress = Progress::new(exact_len(&stream));
+    let stream = Arc::new(Mutex::new(Box::pin(stream.fuse())));
+    let held = Arc::new(Held::new(n));
+    let workers = (0..n).map(|worker| {
+        let stream = Arc::clone(&stream);
         let mut tx = tx.clone();
         let op = op.clone();
-        let task: JoinHandle<Result<(), SendError<U>>> = tokio::spawn(async move {
+        let window = window.clone();
+        let budget = Arc::clone(&budget);
+        let stop = Arc::clone(&stop);
+        let progress = progress.clone();
+        let held = Arc::clone(&held);
+        async move {
+            let mut chunk = VecDeque::new();
+            let mut batch_started = Instant::now();
             loop {
-                let item = {
-                    match iter.lock().await.next() {
-                        Some(item) => item,
-                        None => return Ok(()),
+                let item = match chunk.pop_front() {
+                    Some(item) if finish_chunk || !stop.load(Ordering::SeqCst) => item,
+                    _ => {
+                        if !held.send(worker, &mut tx).await {
+                            return;
+                        }
+                        // The permits are given back by the consumer once the results are
+                        // yielded.
+                        if let Some(window) = &window {
+                            window.acquire().await.forget();
+                        }
+                        if stop.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        pull_chunk(&stream, &mut chunk, n, max_chunk, window.as_deref()).await;
+                        batch_started = Instant::now();
+                        match chunk.pop_front() {
+                            Some(item) => item,
+                            None => return,
+                        }
                     }
                 };
-                tx.send(op(item)).await?;
+                progress.pulled();
+                let result = op(item).await;
+                let failed = is_failure(&result);
+                if failed && stop_on_failure {
+                    stop.store(true, Ordering::SeqCst);
+                }
+                progress.finished(failed);
+                let weight = budget.weigh(&result);
+                if !budget.try_acquire(weight) {
+                    // The consumer can only make room once it has the results held back here.
+                    if !held.send(worker, &mut tx).await {
+                        return;
+                    }
+                    budget.acquire(weight).await;
+                }
+                held.push(worker, result);
+                if batch_started.elapsed() >= MAX_BATCH_DELAY {
+                    if !held.send(worker, &mut tx).await {
+                        return;
+                    }
+                    batch_started = Instant::now();
+                }
             }
-        });
-        task
+        }
     });
-    let handle = future::try_join_all(tasks);
-    ParallelIterator {
-        rx,
-        _handle: handle,
+    let workers = Workers::spawn(&*executor, workers, Arc::clone(&stop));
+    ParallelIterator::new(rx, workers, progress, Some(budget), Some(held))
+}
+
+/// Default upper bound of the number of items a worker takes from the input at once.
+const MAX_CHUNK: usize = 64;
+
+/// Results of a chunk are sent to the consumer together, unless they take longer than this. Cheap
+/// ops then pay for one send per chunk. A consumer that runs out of results takes the ones held
+/// back itself, so that they never wait for a slow op that runs after them.
+const MAX_BATCH_DELAY: Duration = Duration::from_millis(1);
+
+/// Moves the next items of `stream` into `chunk`, leaving it empty at the end of the stream.
+///
+/// Taking a chunk of items per lock keeps the lock from dominating cheap ops. Chunks shrink as
+/// the input runs out, so that the last items are still spread over all `n` workers. Only items
+/// that are ready right away are added after the first one, and each of them needs its own
+/// `window` permit.
+async fn pull_chunk<S, T>(
+    stream: &Mutex<Pin<Box<S>>>,
+    chunk: &mut VecDeque<T>,
+    n: usize,
+    max_chunk: usize,
+    window: Option<&Semaphore>,
+) where
+    S: Stream<Item = T>,
+{
+    let mut stream = stream.lock().await;
+    match stream.next().await {
+        Some(item) => chunk.push_back(item),
+        None => return,
+    }
+    let size = (stream.size_hint().0 / (2 * n)).min(max_chunk);
+    while chunk.len() < size {
+        if let Some(window) = window {
+            match window.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(_) => break,
+            }
+        }
+        match stream.next().now_or_never() {
+            Some(Some(item)) => chunk.push_back(item),
+            _ => {
+                if let Some(window) = window {
+                    window.add_permits(1);
+                }
+                break;
+            }
+        }
     }
 }
 
-struct Promise<T: Send> {
-    handle: JoinHandle<T>,
+fn exact_len<S: Stream>(stream: &S) -> Option<usize> {
+    match stream.size_hint() {
+        (lower, Some(upper)) if lower == upper => Some(lower),
+        _ => None,
+    }
 }
 
-impl<T: 'static + Send> Promise<T> {
-    fn new<F: 'static + Send + FnOnce() -> T>(resolve: F) -> Self {
-        let handle = tokio::task::spawn(async { resolve() });
-        Promise { handle }
+pub struct OrderedParallelIterator<T> {
+    inner: ParallelIterator<(usize, T)>,
+    pending: BTreeMap<usize, T>,
+    next: usize,
+    window: Arc<Semaphore>,
+    workers: usize,
+}
+
+impl<T> OrderedParallelIterator<T> {
+    pub fn progress(&self) -> Progress {
+        self.inner.progress()
+    }
+
+    /// Stops workers from pulling new items. Items already in flight are still yielded, but items
+    /// that workers took from the input in a chunk and haven't started yet are dropped.
+    pub fn cancel(&self) {
+        self.inner.cancel();
+        // The items a worker drops are never yielded, so neither are the permits for the items
+        // behind them. Let every worker waiting for one see the stop instead.
+        self.window.add_permits(self.workers);
+    }
+
+    /// Cancels the workers and waits for the items already in flight. Like `cancel`, drops the
+    /// items that were taken but not started.
+    pub async fn shutdown(self) -> Vec<T> {
+        self.cancel();
+        self.collect().await
     }
 }
 
-impl<T: Send> Future for Promise<T> {
-    type Output = std::result::Result<T, JoinError>;
+impl<T> Stream for OrderedParallelIterator<T> {
+    type Item = T;
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        JoinHandle::poll(Pin::new(&mut self.handle), cx)
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            let next = self.next;
+            if let Some(item) = self.pending.remove(&next) {
+                self.next += 1;
+                self.window.add_permits(1);
+                return Poll::Ready(Some(item));
+            }
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some((i, item)) => {
+                    self.pending.insert(i, item);
+                }
+                None => {
+                    // Only happens after a cancel, when workers drop items they had already
+                    // taken. Yield the rest in order rather than losing it.
+                    let next = match self.pending.keys().next() {
+                        Some(&next) => next,
+                        None => return Poll::Ready(None),
+                    };
+                    self.next = next;
+                }
+            }
+        }
+    }
+}
+
+/// Thread pool for closures that would otherwise block an async worker thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockingPool {
+    /// Tokio's blocking thread pool, via `tokio::task::spawn_blocking`.
+    Tokio,
+    /// Rayon's global thread pool.
+    Rayon,
+}
+
+impl BlockingPool {
+    /// Runs `f` on this pool and resolves to its result. A panic in `f` is resumed on the task
+    /// awaiting the result.
+    pub fn run<F, T>(self, f: F) -> BoxFuture<'static, T>
+    where
+        F: 'static + Send + FnOnce() -> T,
+        T: 'static + Send,
+    {
+        match self {
+            BlockingPool::Tokio => tokio::task::spawn_blocking(f)
+                .map(|result| result.unwrap_or_else(|err| panic::resume_unwind(err.into_panic())))
+                .boxed(),
+            BlockingPool::Rayon => {
+                let (tx, rx) = oneshot::channel();
+                rayon::spawn(move || {
+                    tx.send(panic::catch_unwind(AssertUnwindSafe(f)))
+                        .unwrap_or(());
+                });
+                rx.map(|result| match result.expect("rayon task was dropped") {
+                    Ok(value) => value,
+                    Err(payload) => panic::resume_unwind(payload),
+                })
+                .boxed()
+            }
+        }
     }
 }
 
 #[tokio::main(core_threads = 8)]
 pub async fn run() -> crate::Result<()> {
     use std::time::Duration;
-    use tokio::sync::oneshot;
     use tokio::time::timeout;
     let (_tx, rx) = oneshot::channel::<()>();
     // Wrap the future with a `Timeout` set to expire in 10 milliseconds.
@@ -91,10 +770,15 @@ pub async fn run() -> crate::Result<()> {
 
     let task = timeout(
         Duration::from_millis(10),
-        Promise::new(|| {
+        Promise::blocking(BlockingPool::Tokio, |token| {
             eprintln!("start");
             let started = tokio::time::Instant::now();
-            for _ in 0..100_000_000 {}
+            for _ in 0..100_000_000 {
+                if token.is_cancelled() {
+                    eprintln!("cancelled in {}", started.elapsed().as_secs_f64());
+                    return;
+                }
+            }
             eprintln!("finished in {}", started.elapsed().as_secs_f64());
         }),
     );
@@ -114,7 +798,7 @@ pub async fn run() -> crate::Result<()> {
         .collect();
     eprintln!("{:?}", nums);
 
-    let nums: Vec<usize> = parallel_map(
+    let nums: Vec<usize> = parallel_map_ordered(
         0..10,
         |i| {
             eprintln!("start: {}", i);
@@ -129,3 +813,102 @@ pub async fn run() -> crate::Result<()> {
     eprintln!("{:?}", nums);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use tokio::stream::StreamExt as _;
+
+    use super::*;
+
+    #[tokio::test(core_threads = 2)]
+    async fn fast_result_does_not_wait_for_slow_item_in_same_chunk() {
+        let started = Instant::now();
+        let mut results = parallel_map(
+            0..1000,
+            |i| {
+                if i == 1 {
+                    thread::sleep(Duration::from_secs(1));
+                }
+                i
+            },
+            1,
+        );
+        assert_eq!(results.next().await, Some(0));
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test(core_threads = 2)]
+    async fn ordered_result_does_not_wait_for_slow_item_in_same_chunk() {
+        let started = Instant::now();
+        let mut results = parallel_map_ordered(
+            0..1000,
+            |i| {
+                if i == 1 {
+                    thread::sleep(Duration::from_secs(1));
+                }
+                i
+            },
+            4,
+        );
+        assert_eq!(results.next().await, Some(0));
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test(core_threads = 4)]
+    async fn keyed_runs_distinct_keys_in_parallel_and_same_key_in_order() {
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let items = (0..2).flat_map(|i| (0..4).map(move |key| (key, i)));
+        let started = Instant::now();
+        let results = parallel_map_keyed(
+            items,
+            |&(key, _)| key,
+            {
+                let runs = Arc::clone(&runs);
+                move |(key, i)| {
+                    let start = Instant::now();
+                    thread::sleep(Duration::from_millis(200));
+                    runs.lock().unwrap().push((key, i, start, Instant::now()));
+                }
+            },
+            4,
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(results.len(), 8);
+        // One key at a time would take 1.6s.
+        assert!(started.elapsed() < Duration::from_millis(1000));
+        let runs = runs.lock().unwrap();
+        for key in 0..4 {
+            let of_key = runs.iter().filter(|run| run.0 == key).collect::<Vec<_>>();
+            assert_eq!(of_key.iter().map(|run| run.1).collect::<Vec<_>>(), [0, 1]);
+            assert!(of_key[0].3 <= of_key[1].2);
+        }
+    }
+
+    #[test]
+    fn scoped_map_borrows_and_runs_in_parallel() {
+        let table = (0..8).map(|i| i * i).collect::<Vec<_>>();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        let results = pool.install(|| {
+            parallel_map_scoped(
+                0..8,
+                |i| {
+                    thread::sleep(Duration::from_millis(200));
+                    table[i]
+                },
+                4,
+            )
+        });
+        assert_eq!(results, table);
+        // One item at a time would take 1.6s.
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,194 @@
+use std::future::Future;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use tokio::stream;
+
+use super::config;
+use super::{spawn_workers, ParallelConfig, ParallelIterator, WorkerConfig};
+
+/// Concurrency limit that finds its own level: it grows by one for every limit's worth of items
+/// that succeed, and is cut by a factor whenever an item fails or takes longer than the latency
+/// limit. Clones share the same limit.
+#[derive(Debug, Clone)]
+pub struct AdaptiveLimit {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    min: usize,
+    max: usize,
+    limit: f64,
+    decrease: f64,
+    latency_limit: Option<Duration>,
+    /// Items that started before the last decrease don't decrease the limit again, so that a
+    /// burst of failures only counts once.
+    decreased_at: Instant,
+    active: usize,
+    waiters: Vec<Waker>,
+}
+
+impl AdaptiveLimit {
+    /// Stays between `min` and `max` items at a time, starting at `min`.
+    pub fn new(min: usize, max: usize) -> Self {
+        assert!(0 < min && min <= max, "limits must satisfy 0 < min <= max");
+        let state = State {
+            min,
+            max,
+            limit: min as f64,
+            decrease: 0.5,
+            latency_limit: None,
+            decreased_at: Instant::now(),
+            active: 0,
+            waiters: Vec::new(),
+        };
+        AdaptiveLimit {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Starts at `initial` instead of `min`.
+    pub fn initial(self, initial: usize) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            assert!(
+                state.min <= initial && initial <= state.max,
+                "initial limit out of bounds"
+            );
+            state.limit = initial as f64;
+        }
+        self
+    }
+
+    /// Factor the limit is multiplied with on failure, 0.5 by default.
+    pub fn decrease(self, factor: f64) -> Self {
+        assert!(0.0 < factor && factor < 1.0, "factor must be within (0, 1)");
+        self.state.lock().unwrap().decrease = factor;
+        self
+    }
+
+    /// Treats items that take longer than `latency` as failures.
+    pub fn latency_limit(self, latency: Duration) -> Self {
+        self.state.lock().unwrap().latency_limit = Some(latency);
+        self
+    }
+
+    /// Current number of items allowed at a time.
+    pub fn current(&self) -> usize {
+        self.state.lock().unwrap().current()
+    }
+
+    fn max(&self) -> usize {
+        self.state.lock().unwrap().max
+    }
+
+    async fn acquire(&self) -> Slot<'_> {
+        future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.active < state.current() {
+                state.active += 1;
+                Poll::Ready(())
+            } else {
+                state.waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await;
+        Slot {
+            limit: self,
+            started: Instant::now(),
+            failed: None,
+        }
+    }
+}
+
+impl State {
+    fn current(&self) -> usize {
+        self.limit as usize
+    }
+}
+
+/// Place of an item in the limit. Dropping it before the item finished, e.g. because the map was
+/// dropped, gives the place back without adjusting the limit.
+struct Slot<'a> {
+    limit: &'a AdaptiveLimit,
+    started: Instant,
+    failed: Option<bool>,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.limit.state.lock().unwrap();
+            state.active -= 1;
+            if let Some(failed) = self.failed {
+                let now = Instant::now();
+                let too_slow = match state.latency_limit {
+                    Some(latency) => now - self.started > latency,
+                    None => false,
+                };
+                if failed || too_slow {
+                    if self.started >= state.decreased_at {
+                        state.limit = (state.limit * state.decrease).max(state.min as f64);
+                        state.decreased_at = now;
+                    }
+                } else {
+                    state.limit = (state.limit + 1.0 / state.limit).min(state.max as f64);
+                }
+            }
+            mem::take(&mut state.waiters)
+        };
+        for waiter in waiters {
+            waiter.wake();
+        }
+    }
+}
+
+/// Same as `parallel_map_async`, but for a fallible `op` that runs at most `limit` items at a
+/// time, while `limit` adapts to the errors and latency of `op`.
+///
+/// Unlike `try_parallel_map_async`, errors don't stop the map; every item yields its result.
+/// Items waiting for a place in the limit are counted as in flight.
+///
+/// The map runs as many workers as `limit` allows at most, whatever the workers of `config`.
+/// `config` sets the buffer, the byte limit of the successes and the executor.
+pub fn parallel_map_adaptive<I, F, Fut, T, U, E>(
+    iter: I,
+    op: F,
+    limit: AdaptiveLimit,
+    config: ParallelConfig<U>,
+) -> ParallelIterator<Result<U, E>>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = Result<U, E>> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+    E: 'static + Send,
+{
+    let config = WorkerConfig {
+        is_failure: Result::is_err,
+        // Waiting workers would hold on to their chunks.
+        max_chunk: 1,
+        ..WorkerConfig::new(config.with_workers(limit.max()), config::ok)
+    };
+    spawn_workers(
+        stream::iter(iter),
+        move |item| {
+            let op = op.clone();
+            let limit = limit.clone();
+            async move {
+                let mut slot = limit.acquire().await;
+                let result = op(item).await;
+                slot.failed = Some(result.is_err());
+                drop(slot);
+                result
+            }
+        },
+        config,
+    )
+}
@@ -0,0 +1,116 @@
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::stream::{Stream, StreamExt as _};
+use tokio::time::{self, Delay, Duration};
+
+use super::{ParallelIterator, Progress};
+
+/// Groups the items of `stream` into batches of at most `max_items`. A batch is emitted once it
+/// is full, or `max_delay` after its first item arrived, whichever comes first, and the last
+/// batch is emitted when `stream` ends.
+///
+/// On the input side, `parallel_map_stream(batched(stream::iter(iter), ..), op, n)` hands `op`
+/// whole batches.
+pub fn batched<S: Stream>(stream: S, max_items: usize, max_delay: Duration) -> Batched<S> {
+    assert!(max_items > 0, "batches must hold at least one item");
+    Batched {
+        stream: Box::pin(stream),
+        items: Vec::new(),
+        max_items,
+        max_delay,
+        delay: None,
+        done: false,
+    }
+}
+
+pub struct Batched<S: Stream> {
+    stream: Pin<Box<S>>,
+    items: Vec<S::Item>,
+    max_items: usize,
+    max_delay: Duration,
+    /// Runs out `max_delay` after the first item of the current batch.
+    delay: Option<Delay>,
+    done: bool,
+}
+
+impl<S: Stream> Batched<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    fn take_batch(&mut self) -> Vec<S::Item> {
+        self.delay = None;
+        mem::take(&mut self.items)
+    }
+}
+
+// The stream is pinned in its box, none of the other fields are pinned.
+impl<S: Stream> Unpin for Batched<S> {}
+
+impl<S: Stream> Stream for Batched<S> {
+    type Item = Vec<S::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            match self.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    if self.items.is_empty() {
+                        self.delay = Some(time::delay_for(self.max_delay));
+                    }
+                    self.items.push(item);
+                    if self.items.len() >= self.max_items {
+                        return Poll::Ready(Some(self.take_batch()));
+                    }
+                }
+                Poll::Ready(None) => {
+                    self.done = true;
+                    let items = self.take_batch();
+                    return Poll::Ready(if items.is_empty() { None } else { Some(items) });
+                }
+                Poll::Pending => break,
+            }
+        }
+        let expired = match &mut self.delay {
+            Some(delay) => Pin::new(delay).poll(cx).is_ready(),
+            None => false,
+        };
+        if expired {
+            Poll::Ready(Some(self.take_batch()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> ParallelIterator<T> {
+    /// Groups the results into batches, see `batched`.
+    pub fn batched(self, max_items: usize, max_delay: Duration) -> Batched<Self> {
+        batched(self, max_items, max_delay)
+    }
+}
+
+impl<T> Batched<ParallelIterator<T>> {
+    pub fn progress(&self) -> Progress {
+        self.stream.progress()
+    }
+
+    /// Stops workers from pulling new items. Items already in flight, and the ones waiting for
+    /// their batch, are still yielded. Items taken but not started are dropped, see
+    /// `ParallelIterator::cancel`.
+    pub fn cancel(&self) {
+        self.stream.cancel();
+    }
+
+    /// Cancels the workers and waits for the items already in flight. Like `cancel`, drops the
+    /// items that were taken but not started.
+    pub async fn shutdown(self) -> Vec<Vec<T>> {
+        self.cancel();
+        self.collect().await
+    }
+}
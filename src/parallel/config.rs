@@ -0,0 +1,223 @@
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+
+use futures::future;
+
+use super::{Executor, TokioExecutor, MAX_CHUNK};
+
+type Weigher<U> = Arc<dyn Fn(&U) -> usize + Send + Sync>;
+
+/// Sizes of a parallel map: how many workers run `op`, and how many results they may finish
+/// ahead of the consumer. Also picks the executor the workers run on.
+///
+/// A plain `usize` converts into a config with that many workers, so the maps still accept `n`.
+pub struct ParallelConfig<U> {
+    workers: usize,
+    buffer: Option<usize>,
+    max_bytes: Option<(usize, Weigher<U>)>,
+    executor: Arc<dyn Executor>,
+}
+
+impl<U> ParallelConfig<U> {
+    pub fn new(workers: usize) -> Self {
+        assert!(workers > 0, "a parallel map needs at least one worker");
+        ParallelConfig {
+            workers,
+            buffer: None,
+            max_bytes: None,
+            executor: Arc::new(TokioExecutor),
+        }
+    }
+
+    /// Maximum number of results that are finished but not yielded yet. Workers wait once it is
+    /// reached. Defaults to `64 * workers`.
+    pub fn buffer(mut self, results: usize) -> Self {
+        assert!(results > 0, "buffer must hold at least one result");
+        self.buffer = Some(results);
+        self
+    }
+
+    /// Also caps the total weight of the buffered results, as measured by `weigher`, e.g. the
+    /// length of a downloaded body.
+    ///
+    /// `weigher` is called once when a result is buffered and once when it is yielded, and must
+    /// return the same weight both times. A result heavier than `max_bytes` is still let through
+    /// when nothing else is buffered.
+    pub fn max_buffered_bytes<W>(mut self, max_bytes: usize, weigher: W) -> Self
+    where
+        W: 'static + Send + Sync + Fn(&U) -> usize,
+    {
+        self.max_bytes = Some((max_bytes, Arc::new(weigher)));
+        self
+    }
+
+    /// Replaces the number of workers, for maps that pick it themselves.
+    pub(super) fn with_workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "a parallel map needs at least one worker");
+        self.workers = workers;
+        self
+    }
+
+    /// Runs the workers on `executor` instead of on the current tokio runtime.
+    pub fn executor<X: 'static + Executor>(mut self, executor: X) -> Self {
+        self.executor = Arc::new(executor);
+        self
+    }
+
+    pub(super) fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub(super) fn executor_ref(&self) -> &Arc<dyn Executor> {
+        &self.executor
+    }
+
+    /// Budget of the results of a map that wraps each `U` into a `V`. `project` returns the `U`
+    /// to weigh, results without one only count against `buffer`.
+    pub(super) fn budget<V: 'static>(&self, project: fn(&V) -> Option<&U>) -> Arc<Budget<V>>
+    where
+        U: 'static,
+    {
+        let max_bytes = self.max_bytes.as_ref().map(|(max_bytes, weigher)| {
+            let weigher = Arc::clone(weigher);
+            let weigher: Weigher<V> = Arc::new(move |result| project(result).map_or(0, &*weigher));
+            (*max_bytes, weigher)
+        });
+        Arc::new(Budget {
+            max_results: self.max_results(),
+            max_bytes,
+            state: Mutex::new(BudgetState::default()),
+        })
+    }
+
+    /// Same as `budget`, for a map whose results are the `U`s themselves. Unlike `budget`, it
+    /// doesn't need them to be `'static`.
+    pub(super) fn own_budget(&self) -> Arc<Budget<U>> {
+        Arc::new(Budget {
+            max_results: self.max_results(),
+            max_bytes: self.max_bytes.clone(),
+            state: Mutex::new(BudgetState::default()),
+        })
+    }
+
+    fn max_results(&self) -> usize {
+        self.buffer.unwrap_or(MAX_CHUNK * self.workers)
+    }
+}
+
+impl<U> From<usize> for ParallelConfig<U> {
+    fn from(workers: usize) -> Self {
+        ParallelConfig::new(workers)
+    }
+}
+
+impl<U> Clone for ParallelConfig<U> {
+    fn clone(&self) -> Self {
+        ParallelConfig {
+            workers: self.workers,
+            buffer: self.buffer,
+            max_bytes: self.max_bytes.clone(),
+            executor: Arc::clone(&self.executor),
+        }
+    }
+}
+
+impl<U> fmt::Debug for ParallelConfig<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ParallelConfig")
+            .field("workers", &self.workers)
+            .field("buffer", &self.buffer)
+            .field(
+                "max_bytes",
+                &self.max_bytes.as_ref().map(|(max_bytes, _)| max_bytes),
+            )
+            .finish()
+    }
+}
+
+/// Projections for `ParallelConfig::budget`.
+pub(super) fn itself<U>(result: &U) -> Option<&U> {
+    Some(result)
+}
+
+pub(super) fn ok<U, E>(result: &Result<U, E>) -> Option<&U> {
+    result.as_ref().ok()
+}
+
+pub(super) fn second<A, B>(pair: &(A, B)) -> Option<&B> {
+    Some(&pair.1)
+}
+
+/// Results that workers have finished but the consumer hasn't yielded yet. Workers take their
+/// share before buffering a result, the consumer gives it back when yielding it.
+pub(super) struct Budget<T> {
+    max_results: usize,
+    max_bytes: Option<(usize, Weigher<T>)>,
+    state: Mutex<BudgetState>,
+}
+
+#[derive(Default)]
+struct BudgetState {
+    results: usize,
+    bytes: usize,
+    waiters: Vec<Waker>,
+}
+
+impl<T> Budget<T> {
+    pub(super) fn max_results(&self) -> usize {
+        self.max_results
+    }
+
+    pub(super) fn weigh(&self, result: &T) -> usize {
+        match &self.max_bytes {
+            Some((_, weigher)) => weigher(result),
+            None => 0,
+        }
+    }
+
+    pub(super) fn try_acquire(&self, weight: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.take(&mut state, weight)
+    }
+
+    pub(super) async fn acquire(&self, weight: usize) {
+        future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if self.take(&mut state, weight) {
+                Poll::Ready(())
+            } else {
+                state.waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    pub(super) fn release(&self, weight: usize) {
+        let waiters = {
+            let mut state = self.state.lock().unwrap();
+            state.results -= 1;
+            state.bytes -= weight;
+            mem::take(&mut state.waiters)
+        };
+        for waiter in waiters {
+            waiter.wake();
+        }
+    }
+
+    fn take(&self, state: &mut BudgetState, weight: usize) -> bool {
+        if state.results >= self.max_results {
+            return false;
+        }
+        if let Some((max_bytes, _)) = &self.max_bytes {
+            if state.bytes > 0 && state.bytes + weight > *max_bytes {
+                return false;
+            }
+        }
+        state.results += 1;
+        state.bytes += weight;
+        true
+    }
+}
@@ -0,0 +1,150 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::channel::oneshot;
+use futures::future::{BoxFuture, FutureExt as _};
+use futures::ready;
+use futures::task::{self, ArcWake};
+use tokio::runtime::Handle;
+
+/// Runs the tasks behind parallel maps and promises.
+///
+/// Tasks only rely on tokio for timers, e.g. in `parallel_map_timeout`, and for
+/// `BlockingPool::Tokio`. Everything else runs on any executor, including a single-threaded one
+/// that makes tests deterministic.
+pub trait Executor: Send + Sync {
+    /// Runs `future` to completion in the background.
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+}
+
+impl<X: Executor + ?Sized> Executor for Arc<X> {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        (**self).spawn(future)
+    }
+}
+
+/// Spawns onto the tokio runtime the caller is running on.
+///
+/// # Panics
+///
+/// Spawning panics outside of a tokio runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioExecutor;
+
+impl Executor for TokioExecutor {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+}
+
+/// Spawns onto the runtime of the handle, so that a library can keep its maps on a runtime of
+/// its own.
+impl Executor for Handle {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        Handle::spawn(self, future);
+    }
+}
+
+/// Polls tasks on a rayon thread pool, the global one unless another pool is given.
+///
+/// A task only occupies a thread while it is being polled, so waiting tasks don't block the
+/// pool.
+#[derive(Debug, Clone, Default)]
+pub struct RayonExecutor {
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl RayonExecutor {
+    pub fn global() -> Self {
+        Default::default()
+    }
+
+    pub fn new(pool: Arc<rayon::ThreadPool>) -> Self {
+        RayonExecutor { pool: Some(pool) }
+    }
+}
+
+impl Executor for RayonExecutor {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        let task = RayonTask {
+            future: Mutex::new(Some(future)),
+            pool: self.pool.clone(),
+        };
+        Arc::new(task).schedule();
+    }
+}
+
+struct RayonTask {
+    /// `None` once the future has finished.
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl RayonTask {
+    fn schedule(self: Arc<Self>) {
+        let pool = self.pool.clone();
+        let poll = move || self.poll();
+        match pool {
+            Some(pool) => pool.spawn(poll),
+            None => rayon::spawn(poll),
+        }
+    }
+
+    fn poll(self: Arc<Self>) {
+        // A wake-up during the poll schedules another one, which waits here for this one.
+        let mut future = self.future.lock().unwrap();
+        let waker = task::waker_ref(&self);
+        let mut cx = Context::from_waker(&waker);
+        if let Some(Poll::Ready(())) = future.as_mut().map(|future| future.as_mut().poll(&mut cx)) {
+            *future = None;
+        }
+    }
+}
+
+impl ArcWake for RayonTask {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        Arc::clone(arc_self).schedule();
+    }
+}
+
+/// Spawns `future` onto `executor`, catching its panic for the returned handle.
+pub(super) fn spawn<Fut>(executor: &dyn Executor, future: Fut) -> JoinHandle<Fut::Output>
+where
+    Fut: 'static + Future + Send,
+    Fut::Output: Send,
+{
+    let (tx, rx) = oneshot::channel();
+    let task = AssertUnwindSafe(future).catch_unwind().map(|result| {
+        // The handle may be gone already, and then nobody wants the result.
+        let _ = tx.send(result);
+    });
+    executor.spawn(task.boxed());
+    JoinHandle { rx }
+}
+
+/// Resolves to the output of a task started by `spawn`. Dropping it doesn't stop the task.
+pub(super) struct JoinHandle<T> {
+    rx: oneshot::Receiver<Result<T, Box<dyn Any + Send>>>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Poll::Ready(match ready!(Pin::new(&mut self.rx).poll(cx)) {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(payload)) => Err(JoinError::Panicked(payload)),
+            // The executor dropped the task, e.g. because its runtime shut down.
+            Err(oneshot::Canceled) => Err(JoinError::Cancelled),
+        })
+    }
+}
+
+pub(super) enum JoinError {
+    Panicked(Box<dyn Any + Send>),
+    Cancelled,
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use futures::future::{BoxFuture, FutureExt as _};
+use futures::stream::{self as futures_stream, BoxStream, StreamExt as _};
+use tokio::stream::{self, Stream};
+
+use super::{parallel_map_stream, try_parallel_map_stream, ParallelIterator, MAX_CHUNK};
+
+/// Async counterparts of rayon's parallel iterator methods, running `op` on up to `n` items at
+/// a time. See `ParallelIteratorExt` for iterators.
+pub trait ParallelStreamExt: 'static + Stream + Send + Sized
+where
+    Self::Item: 'static + Send,
+{
+    fn par_map<F, Fut, U>(self, n: usize, op: F) -> ParallelIterator<U>
+    where
+        F: 'static + Send + Sync + Clone + Fn(Self::Item) -> Fut,
+        Fut: Future<Output = U> + Send,
+        U: 'static + Send,
+    {
+        parallel_map_stream(self, op, n)
+    }
+
+    fn par_for_each<F, Fut>(self, n: usize, op: F) -> BoxFuture<'static, ()>
+    where
+        F: 'static + Send + Sync + Clone + Fn(Self::Item) -> Fut,
+        Fut: Future<Output = ()> + Send,
+    {
+        parallel_map_stream(self, op, n)
+            .for_each(|()| async {})
+            .boxed()
+    }
+
+    /// Stops at the first error, which is returned once the workers are aborted.
+    fn par_try_for_each<F, Fut, E>(self, n: usize, op: F) -> BoxFuture<'static, Result<(), E>>
+    where
+        F: 'static + Send + Sync + Clone + Fn(Self::Item) -> Fut,
+        Fut: Future<Output = Result<(), E>> + Send,
+        E: 'static + Send,
+    {
+        let mut results = try_parallel_map_stream(self, op, n);
+        async move {
+            while let Some(result) = results.next().await {
+                result?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn par_filter_map<F, Fut, U>(self, n: usize, op: F) -> BoxStream<'static, U>
+    where
+        F: 'static + Send + Sync + Clone + Fn(Self::Item) -> Fut,
+        Fut: Future<Output = Option<U>> + Send,
+        U: 'static + Send,
+    {
+        parallel_map_stream(self, op, n)
+            .filter_map(|item| async { item })
+            .boxed()
+    }
+
+    /// Folds the items with `fold` in parallel, and merges the partial results with `combine`.
+    /// Each worker folds a run of consecutive items into `identity()`, and the runs are merged in
+    /// the order of the input, so `combine` must be associative, and `identity()` must be neutral
+    /// for it.
+    fn par_fold<Acc, ID, F, Fut, C>(
+        self,
+        n: usize,
+        identity: ID,
+        fold: F,
+        mut combine: C,
+    ) -> BoxFuture<'static, Acc>
+    where
+        Acc: 'static + Send,
+        ID: 'static + Send + Sync + Clone + Fn() -> Acc,
+        F: 'static + Send + Sync + Clone + Fn(Acc, Self::Item) -> Fut,
+        Fut: Future<Output = Acc> + Send,
+        C: 'static + Send + FnMut(Acc, Acc) -> Acc,
+    {
+        // Sized like the chunks of `parallel_map`, so that the runs are still spread over all
+        // workers.
+        let len = match self.size_hint().0 / (2 * n).max(1) {
+            0 => 1,
+            len => len.min(MAX_CHUNK),
+        };
+        // Like `pull_chunk`, a run only waits for its first item.
+        let runs = futures_stream::unfold(Box::pin(self.fuse()), move |mut items| async move {
+            let mut run = vec![items.next().await?];
+            while run.len() < len {
+                match items.next().now_or_never() {
+                    Some(Some(item)) => run.push(item),
+                    _ => break,
+                }
+            }
+            Some((run, items))
+        })
+        .enumerate();
+        let init = identity.clone();
+        let mut partials = parallel_map_stream(
+            runs,
+            move |(i, run)| {
+                let identity = identity.clone();
+                let fold = fold.clone();
+                async move {
+                    let mut acc = identity();
+                    for item in run {
+                        acc = fold(acc, item).await;
+                    }
+                    (i, acc)
+                }
+            },
+            n,
+        );
+        async move {
+            // Runs that finished before the ones in front of them.
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            let mut acc = None;
+            while let Some((i, partial)) = partials.next().await {
+                pending.insert(i, partial);
+                while let Some(partial) = pending.remove(&next) {
+                    acc = Some(match acc {
+                        Some(acc) => combine(acc, partial),
+                        None => partial,
+                    });
+                    next += 1;
+                }
+            }
+            acc.unwrap_or_else(init)
+        }
+        .boxed()
+    }
+}
+
+impl<S> ParallelStreamExt for S
+where
+    S: 'static + Stream + Send + Sized,
+    S::Item: 'static + Send,
+{
+}
+
+/// Same as `ParallelStreamExt`, for iterators.
+pub trait ParallelIteratorExt: 'static + Iterator + Send + Sized
+where
+    Self::Item: 'static + Send,
+{
+    fn par_map<F, Fut, U>(self, n: usize, op: F) -> ParallelIterator<U>
+    where
+        F: 'static + Send + Sync + Clone + Fn(Self::Item) -> Fut,
+        Fut: Future<Output = U> + Send,
+        U: 'static + Send,
+    {
+        stream::iter(self).par_map(n, op)
+    }
+
+    fn par_for_each<F, Fut>(self, n: usize, op: F) -> BoxFuture<'static, ()>
+    where
+        F: 'static + Send + Sync + Clone + Fn(Self::Item) -> Fut,
+        Fut: Future<Output = ()> + Send,
+    {
+        stream::iter(self).par_for_each(n, op)
+    }
+
+    fn par_try_for_each<F, Fut, E>(self, n: usize, op: F) -> BoxFuture<'static, Result<(), E>>
+    where
+        F: 'static + Send + Sync + Clone + Fn(Self::Item) -> Fut,
+        Fut: Future<Output = Result<(), E>> + Send,
+        E: 'static + Send,
+    {
+        stream::iter(self).par_try_for_each(n, op)
+    }
+
+    fn par_filter_map<F, Fut, U>(self, n: usize, op: F) -> BoxStream<'static, U>
+    where
+        F: 'static + Send + Sync + Clone + Fn(Self::Item) -> Fut,
+        Fut: Future<Output = Option<U>> + Send,
+        U: 'static + Send,
+    {
+        stream::iter(self).par_filter_map(n, op)
+    }
+
+    fn par_fold<Acc, ID, F, Fut, C>(
+        self,
+        n: usize,
+        identity: ID,
+        fold: F,
+        combine: C,
+    ) -> BoxFuture<'static, Acc>
+    where
+        Acc: 'static + Send,
+        ID: 'static + Send + Sync + Clone + Fn() -> Acc,
+        F: 'static + Send + Sync + Clone + Fn(Acc, Self::Item) -> Fut,
+        Fut: Future<Output = Acc> + Send,
+        C: 'static + Send + FnMut(Acc, Acc) -> Acc,
+    {
+        stream::iter(self).par_fold(n, identity, fold, combine)
+    }
+}
+
+impl<I> ParallelIteratorExt for I
+where
+    I: 'static + Iterator + Send + Sized,
+    I::Item: 'static + Send,
+{
+}
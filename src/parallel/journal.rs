@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::hash::Hash;
+use std::io::{self, Write as _};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use futures::future;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::stream;
+
+use super::config;
+use super::{spawn_workers, ParallelConfig, ParallelIterator, TryParallelIterator, WorkerConfig};
+
+/// Same as `parallel_map`, but records the result of every finished item in the journal at
+/// `path`, under the key `key_fn` returns for the item. When the map runs again with the same
+/// journal, items that already finished are not run again, and their recorded results are
+/// yielded instead.
+///
+/// The journal holds one JSON line per item. A line cut short by a crash is dropped when the
+/// journal is opened. Keys should be unique within `iter`.
+///
+/// # Panics
+///
+/// The map panics if a result can't be written to the journal.
+pub fn parallel_map_journaled<I, KF, K, F, T, U>(
+    iter: I,
+    key_fn: KF,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+    path: impl AsRef<Path>,
+) -> io::Result<ParallelIterator<U>>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    KF: 'static + Send + Sync + Fn(&T) -> K,
+    K: 'static + Eq + Hash + Serialize + DeserializeOwned + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> U,
+    T: 'static + Send,
+    U: 'static + Serialize + DeserializeOwned + Send,
+{
+    parallel_map_journaled_async(
+        iter,
+        key_fn,
+        move |item| future::ready(op(item)),
+        config,
+        path,
+    )
+}
+
+/// Same as `parallel_map_journaled`, but `op` returns a future.
+pub fn parallel_map_journaled_async<I, KF, K, F, Fut, T, U>(
+    iter: I,
+    key_fn: KF,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+    path: impl AsRef<Path>,
+) -> io::Result<ParallelIterator<U>>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    KF: 'static + Send + Sync + Fn(&T) -> K,
+    K: 'static + Eq + Hash + Serialize + DeserializeOwned + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = U> + Send,
+    T: 'static + Send,
+    U: 'static + Serialize + DeserializeOwned + Send,
+{
+    let journal = Journal::open(path.as_ref())?;
+    let config = WorkerConfig::new(config.into(), config::itself);
+    Ok(journal.spawn(iter, key_fn, op, config, config::itself, |result| result))
+}
+
+/// Same as `parallel_map_journaled_async`, but for a fallible `op`. Only successes are recorded,
+/// so items that failed run again on the next run.
+///
+/// Like `try_parallel_map_async`, the map stops after the first error.
+pub fn try_parallel_map_journaled_async<I, KF, K, F, Fut, T, U, E>(
+    iter: I,
+    key_fn: KF,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+    path: impl AsRef<Path>,
+) -> io::Result<TryParallelIterator<U, E>>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    KF: 'static + Send + Sync + Fn(&T) -> K,
+    K: 'static + Eq + Hash + Serialize + DeserializeOwned + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = Result<U, E>> + Send,
+    T: 'static + Send,
+    U: 'static + Serialize + DeserializeOwned + Send,
+    E: 'static + Send,
+{
+    let journal = Journal::open(path.as_ref())?;
+    let config = WorkerConfig {
+        is_failure: Result::is_err,
+        stop_on_failure: true,
+        ..WorkerConfig::new(config.into(), config::ok)
+    };
+    Ok(TryParallelIterator {
+        inner: journal.spawn(iter, key_fn, op, config, config::ok, Ok),
+        done: false,
+    })
+}
+
+/// Results recorded by earlier runs, and the file that new ones are appended to.
+struct Journal<K, U> {
+    recorded: HashMap<K, U>,
+    file: File,
+}
+
+impl<K, U> Journal<K, U>
+where
+    K: 'static + Eq + Hash + Serialize + DeserializeOwned + Send,
+    U: 'static + Serialize + DeserializeOwned + Send,
+{
+    fn open(path: &Path) -> io::Result<Self> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let mut recorded = HashMap::new();
+        let mut complete = 0;
+        // Only lines that end in a newline were written completely.
+        while let Some(len) = contents[complete..].iter().position(|&b| b == b'\n') {
+            let line = &contents[complete..complete + len];
+            let (key, result) = serde_json::from_slice(line)?;
+            recorded.insert(key, result);
+            complete += len + 1;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // Drops a line cut short by a crash, so that new lines follow the last complete one.
+        file.set_len(complete as u64)?;
+        Ok(Journal { recorded, file })
+    }
+
+    /// Spawns the workers of a map whose results are `R`. `record` picks the part of a result
+    /// that is recorded, and `replay` turns a recorded value back into a result.
+    fn spawn<I, KF, F, Fut, T, R>(
+        self,
+        iter: I,
+        key_fn: KF,
+        op: F,
+        config: WorkerConfig<R>,
+        record: fn(&R) -> Option<&U>,
+        replay: fn(U) -> R,
+    ) -> ParallelIterator<R>
+    where
+        I: 'static + Iterator<Item = T> + Send,
+        KF: 'static + Send + Sync + Fn(&T) -> K,
+        F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+        Fut: Future<Output = R> + Send,
+        T: 'static + Send,
+        R: 'static + Send,
+    {
+        let key_fn = Arc::new(key_fn);
+        let recorded = Arc::new(Mutex::new(self.recorded));
+        let file = Arc::new(Mutex::new(self.file));
+        spawn_workers(
+            stream::iter(iter),
+            move |item| {
+                let key = key_fn(&item);
+                let replayed = recorded.lock().unwrap().remove(&key);
+                let op = op.clone();
+                let file = Arc::clone(&file);
+                async move {
+                    if let Some(value) = replayed {
+                        return replay(value);
+                    }
+                    let result = op(item).await;
+                    if let Some(value) = record(&result) {
+                        let mut line = serde_json::to_vec(&(&key, value))
+                            .unwrap_or_else(|err| panic!("failed to serialize result: {}", err));
+                        line.push(b'\n');
+                        // A single write per line, so that a crash can only cut the last line.
+                        file.lock()
+                            .unwrap()
+                            .write_all(&line)
+                            .unwrap_or_else(|err| panic!("failed to write journal: {}", err));
+                    }
+                    result
+                }
+            },
+            config,
+        )
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::Hash;
+use std::iter;
+use std::panic;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+
+use futures::future::{self, AbortHandle, Abortable, Aborted};
+use futures::stream::FuturesUnordered;
+use tokio::stream::{self, Stream, StreamExt as _};
+use tokio::sync::mpsc::{self, Sender};
+
+use super::config::{self, Budget};
+use super::executor::{self, Executor, JoinError, JoinHandle};
+use super::{exact_len, ParallelConfig, ParallelIterator, Progress, Workers};
+
+/// Same as `parallel_map`, but items with the same key are processed one at a time, in the order
+/// of `iter`. Items with different keys run in parallel, up to one key per worker of `config` at a
+/// time.
+pub fn parallel_map_keyed<I, KF, K, F, T, U>(
+    iter: I,
+    key_fn: KF,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> ParallelIterator<U>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    KF: 'static + Send + Fn(&T) -> K,
+    K: 'static + Eq + Hash + Clone + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> U,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    // `op` runs inside the spawned task, not on the dispatcher, so that keys run in parallel.
+    let op = move |item| {
+        let op = op.clone();
+        async move { op(item) }
+    };
+    parallel_map_keyed_async(iter, key_fn, op, config)
+}
+
+/// Same as `parallel_map_keyed`, but `op` returns a future.
+pub fn parallel_map_keyed_async<I, KF, K, F, Fut, T, U>(
+    iter: I,
+    key_fn: KF,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> ParallelIterator<U>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    KF: 'static + Send + Fn(&T) -> K,
+    K: 'static + Eq + Hash + Clone + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: 'static + Future<Output = U> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    let config = config.into();
+    let budget = config.budget(config::itself);
+    let input = stream::iter(iter);
+    let (tx, rx) = mpsc::channel(budget.max_results());
+    let progress = Progress::new(exact_len(&input));
+    let stop = Arc::new(AtomicBool::new(false));
+    let dispatcher = Dispatcher {
+        key_fn,
+        op,
+        n: config.workers(),
+        executor: Arc::clone(config.executor_ref()),
+        budget: Arc::clone(&budget),
+        active: HashMap::new(),
+        queued: 0,
+        blocked: None,
+        running: Running::default(),
+        tx,
+        stop: Arc::clone(&stop),
+        progress: progress.clone(),
+    };
+    let workers = Workers::spawn(
+        &**config.executor_ref(),
+        iter::once(dispatcher.run(input)),
+        stop,
+    );
+    ParallelIterator::new(rx, workers, progress, Some(budget), None)
+}
+
+/// Single task that pulls the input and starts a task per item once its key is free.
+struct Dispatcher<KF, K, F, T, U> {
+    key_fn: KF,
+    op: F,
+    n: usize,
+    executor: Arc<dyn Executor>,
+    budget: Arc<Budget<U>>,
+    /// Keys with a running item, each with the items of the same key waiting behind it.
+    active: HashMap<K, VecDeque<T>>,
+    /// Number of items waiting in `active`.
+    queued: usize,
+    /// Item whose key is not active, waiting until fewer than `n` keys are.
+    blocked: Option<(K, T)>,
+    running: Running<K, U>,
+    tx: Sender<Vec<U>>,
+    stop: Arc<AtomicBool>,
+    progress: Progress,
+}
+
+enum Event<K, T, U> {
+    Pulled(Option<T>),
+    Finished(Result<Result<(K, U), Aborted>, JoinError>),
+}
+
+impl<KF, K, F, Fut, T, U> Dispatcher<KF, K, F, T, U>
+where
+    KF: Fn(&T) -> K,
+    K: 'static + Eq + Hash + Clone + Send,
+    F: Fn(T) -> Fut,
+    Fut: 'static + Future<Output = U> + Send,
+    U: 'static + Send,
+{
+    async fn run<S: Stream<Item = T>>(mut self, input: S) {
+        let mut input = Box::pin(input.fuse());
+        let mut input_done = false;
+        loop {
+            // Waiting items are bounded by `n` as well, so that a hot key can't make the whole
+            // input pile up in memory.
+            let can_pull = !input_done
+                && self.blocked.is_none()
+                && self.queued < self.n
+                && !self.stop.load(Ordering::SeqCst);
+            if !can_pull && self.running.tasks.is_empty() {
+                return;
+            }
+            let running = &mut self.running.tasks;
+            let event = future::poll_fn(|cx| {
+                if let Poll::Ready(Some(result)) = Pin::new(&mut *running).poll_next(cx) {
+                    return Poll::Ready(Event::Finished(result));
+                }
+                if can_pull {
+                    if let Poll::Ready(item) = input.as_mut().poll_next(cx) {
+                        return Poll::Ready(Event::Pulled(item));
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+            match event {
+                Event::Pulled(Some(item)) => self.dispatch(item),
+                Event::Pulled(None) => input_done = true,
+                Event::Finished(Ok(Ok((key, result)))) => {
+                    self.progress.finished(false);
+                    self.budget.acquire(self.budget.weigh(&result)).await;
+                    if self.tx.send(vec![result]).await.is_err() {
+                        return;
+                    }
+                    self.next_of(key);
+                }
+                // Tasks are only aborted when the dispatcher itself is dropped.
+                Event::Finished(Ok(Err(Aborted))) => return,
+                Event::Finished(Err(JoinError::Panicked(payload))) => panic::resume_unwind(payload),
+                Event::Finished(Err(JoinError::Cancelled)) => return,
+            }
+        }
+    }
+
+    fn dispatch(&mut self, item: T) {
+        let key = (self.key_fn)(&item);
+        if let Some(queue) = self.active.get_mut(&key) {
+            queue.push_back(item);
+            self.queued += 1;
+        } else if self.active.len() < self.n {
+            self.start(key, item);
+        } else {
+            self.blocked = Some((key, item));
+        }
+    }
+
+    /// Starts the next item of `key`, whose previous item just finished, or frees its slot.
+    fn next_of(&mut self, key: K) {
+        self.running.aborts.remove(&key);
+        if let Some(item) = self.active.get_mut(&key).and_then(VecDeque::pop_front) {
+            self.queued -= 1;
+            self.start_task(key, item);
+            return;
+        }
+        self.active.remove(&key);
+        if let Some((key, item)) = self.blocked.take() {
+            self.start(key, item);
+        }
+    }
+
+    fn start(&mut self, key: K, item: T) {
+        self.active.insert(key.clone(), VecDeque::new());
+        self.start_task(key, item);
+    }
+
+    fn start_task(&mut self, key: K, item: T) {
+        self.progress.pulled();
+        let result = (self.op)(item);
+        let (abort, registration) = AbortHandle::new_pair();
+        self.running.aborts.insert(key.clone(), abort);
+        let task = async move { (key, result.await) };
+        let task = executor::spawn(&*self.executor, Abortable::new(task, registration));
+        self.running.tasks.push(task);
+    }
+}
+
+/// Tasks started by the dispatcher, which are aborted together with it.
+struct Running<K, U> {
+    tasks: FuturesUnordered<JoinHandle<Result<(K, U), Aborted>>>,
+    aborts: HashMap<K, AbortHandle>,
+}
+
+impl<K, U> Default for Running<K, U> {
+    fn default() -> Self {
+        Running {
+            tasks: FuturesUnordered::new(),
+            aborts: HashMap::new(),
+        }
+    }
+}
+
+impl<K, U> Drop for Running<K, U> {
+    fn drop(&mut self) {
+        for abort in self.aborts.values() {
+            abort.abort();
+        }
+    }
+}
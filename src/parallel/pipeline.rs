@@ -0,0 +1,118 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::FutureExt as _;
+use futures::stream::BoxStream;
+use tokio::stream::{self, Stream, StreamExt as _};
+
+use super::{try_parallel_map_stream, ParallelConfig, Progress};
+
+/// Chain of parallel maps, each with its own workers and buffer. A stage only pulls as fast as
+/// the next one takes its results, so the slowest stage sets the pace of the whole chain.
+///
+/// The first error of any stage is passed on through the later stages and ends the pipeline,
+/// which then drops all items still in flight. It also counts as a failure in the progress of
+/// every stage it passes through.
+///
+/// Stages start as they are added, so a pipeline has to be built on a runtime. An infallible
+/// pipeline still needs an error type, e.g. `Pipeline::<_, Infallible>::new(iter)`.
+pub struct Pipeline<T, E> {
+    stream: BoxStream<'static, Result<T, E>>,
+    stages: Vec<(String, Progress)>,
+    /// Stop flag of the first stage, which stops the pipeline from pulling its input.
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl<T: 'static + Send, E: 'static + Send> Pipeline<T, E> {
+    pub fn new<I>(iter: I) -> Self
+    where
+        I: 'static + Iterator<Item = T> + Send,
+    {
+        Pipeline::from_stream(stream::iter(iter))
+    }
+
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: 'static + Stream<Item = T> + Send,
+    {
+        Pipeline {
+            stream: Box::pin(stream.map(Ok)),
+            stages: Vec::new(),
+            stop: None,
+        }
+    }
+
+    /// Adds a stage that runs `op` on every item on the workers of `config`.
+    pub fn stage<F, Fut, U>(
+        self,
+        name: impl Into<String>,
+        config: impl Into<ParallelConfig<U>>,
+        op: F,
+    ) -> Pipeline<U, E>
+    where
+        F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+        Fut: Future<Output = U> + Send,
+        U: 'static + Send,
+    {
+        self.try_stage(name, config, move |item| op(item).map(Ok))
+    }
+
+    /// Same as `stage`, but for a fallible `op`. The first error ends the pipeline.
+    pub fn try_stage<F, Fut, U>(
+        self,
+        name: impl Into<String>,
+        config: impl Into<ParallelConfig<U>>,
+        op: F,
+    ) -> Pipeline<U, E>
+    where
+        F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+        Fut: Future<Output = Result<U, E>> + Send,
+        U: 'static + Send,
+    {
+        let stage = try_parallel_map_stream(
+            self.stream,
+            move |item| {
+                let op = op.clone();
+                async move { op(item?).await }
+            },
+            config,
+        );
+        let mut stages = self.stages;
+        stages.push((name.into(), stage.progress()));
+        let stop = self
+            .stop
+            .unwrap_or_else(|| Arc::clone(&stage.inner.workers.stop));
+        Pipeline {
+            stream: Box::pin(stage),
+            stages,
+            stop: Some(stop),
+        }
+    }
+}
+
+impl<T, E> Pipeline<T, E> {
+    /// Progress of every stage, by name, in the order the stages were added.
+    pub fn progress(&self) -> Vec<(String, Progress)> {
+        self.stages.clone()
+    }
+
+    /// Stops the first stage from pulling new items. Items already in the pipeline still pass
+    /// through the remaining stages, except for the ones the first stage took but hadn't started,
+    /// which are dropped like on `ParallelIterator::cancel`.
+    pub fn cancel(&self) {
+        if let Some(stop) = &self.stop {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<T, E> Stream for Pipeline<T, E> {
+    type Item = Result<T, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
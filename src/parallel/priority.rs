@@ -0,0 +1,66 @@
+use std::future::Future;
+
+use futures::future;
+use tokio::stream;
+
+use super::config;
+use super::{spawn_workers, ParallelConfig, ParallelIterator, WorkerConfig};
+
+/// Same as `parallel_map`, but workers always take the pending item with the highest
+/// `priority_fn`. Items of equal priority run in the order of `iter`.
+///
+/// Returning the expected cost of an item, e.g. the previous runtime of a test, schedules the
+/// longest items first, which keeps a single long item from finishing last on an otherwise idle
+/// pool.
+///
+/// `iter` is consumed when this is called, to know the priority of every item up front.
+pub fn parallel_map_prioritized<I, PF, P, F, T, U>(
+    iter: I,
+    priority_fn: PF,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> ParallelIterator<U>
+where
+    I: Iterator<Item = T>,
+    PF: FnMut(&T) -> P,
+    P: Ord,
+    F: 'static + Send + Sync + Clone + Fn(T) -> U,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    parallel_map_prioritized_async(
+        iter,
+        priority_fn,
+        move |item| future::ready(op(item)),
+        config,
+    )
+}
+
+/// Same as `parallel_map_prioritized`, but `op` returns a future.
+pub fn parallel_map_prioritized_async<I, PF, P, F, Fut, T, U>(
+    iter: I,
+    mut priority_fn: PF,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+) -> ParallelIterator<U>
+where
+    I: Iterator<Item = T>,
+    PF: FnMut(&T) -> P,
+    P: Ord,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = U> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    let mut items = iter
+        .map(|item| (priority_fn(&item), item))
+        .collect::<Vec<_>>();
+    // Stable, so that ties keep their order.
+    items.sort_by(|(a, _), (b, _)| b.cmp(a));
+    let items = items.into_iter().map(|(_, item)| item).collect::<Vec<_>>();
+    let config = WorkerConfig {
+        max_chunk: 1,
+        ..WorkerConfig::new(config.into(), config::itself)
+    };
+    spawn_workers(stream::iter(items), op, config)
+}
@@ -0,0 +1,138 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+/// Live counters of a running parallel map. Clones share the same counters.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    started: Instant,
+    total: Option<usize>,
+    pulled: AtomicUsize,
+    completed: AtomicUsize,
+    failed: AtomicUsize,
+    watched: AtomicBool,
+    tx: watch::Sender<ProgressSnapshot>,
+    rx: watch::Receiver<ProgressSnapshot>,
+}
+
+/// Counters of a `Progress` at one point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressSnapshot {
+    /// Items taken from the input so far.
+    pub pulled: usize,
+    /// Items pulled but not finished yet.
+    pub in_flight: usize,
+    /// Items that finished successfully.
+    pub completed: usize,
+    /// Items that finished with an error. Only fallible maps count failures.
+    pub failed: usize,
+    /// Number of input items, if the input knows its exact length.
+    pub total: Option<usize>,
+    pub elapsed: Duration,
+}
+
+impl ProgressSnapshot {
+    pub fn finished(&self) -> usize {
+        self.completed + self.failed
+    }
+
+    /// Finished items per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.finished() as f64 / secs
+        }
+    }
+
+    /// Estimated time until all items are finished, based on the throughput so far.
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self.total?.saturating_sub(self.finished());
+        let throughput = self.throughput();
+        if throughput == 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(remaining as f64 / throughput))
+    }
+}
+
+impl Progress {
+    pub(super) fn new(total: Option<usize>) -> Self {
+        let snapshot = ProgressSnapshot {
+            pulled: 0,
+            in_flight: 0,
+            completed: 0,
+            failed: 0,
+            total,
+            elapsed: Duration::from_secs(0),
+        };
+        let (tx, rx) = watch::channel(snapshot);
+        let inner = Inner {
+            started: Instant::now(),
+            total,
+            pulled: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            watched: AtomicBool::new(false),
+            tx,
+            rx,
+        };
+        Progress {
+            inner: Arc::new(inner),
+        }
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let inner = &self.inner;
+        let pulled = inner.pulled.load(Ordering::SeqCst);
+        let completed = inner.completed.load(Ordering::SeqCst);
+        let failed = inner.failed.load(Ordering::SeqCst);
+        ProgressSnapshot {
+            pulled,
+            in_flight: pulled.saturating_sub(completed + failed),
+            completed,
+            failed,
+            total: inner.total,
+            elapsed: inner.started.elapsed(),
+        }
+    }
+
+    /// Returns a receiver that sees a new snapshot on every change. It ends once the map and all
+    /// `Progress` handles are dropped.
+    pub fn subscribe(&self) -> watch::Receiver<ProgressSnapshot> {
+        self.inner.watched.store(true, Ordering::SeqCst);
+        let rx = self.inner.rx.clone();
+        self.notify();
+        rx
+    }
+
+    pub(super) fn pulled(&self) {
+        self.inner.pulled.fetch_add(1, Ordering::SeqCst);
+        self.notify();
+    }
+
+    pub(super) fn finished(&self, failed: bool) {
+        let counter = if failed {
+            &self.inner.failed
+        } else {
+            &self.inner.completed
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        if self.inner.watched.load(Ordering::SeqCst) {
+            // Can't fail, since `inner` keeps a receiver alive.
+            self.inner.tx.broadcast(self.snapshot()).unwrap_or(());
+        }
+    }
+}
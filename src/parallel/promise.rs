@@ -0,0 +1,373 @@
+use std::any::Any;
+use std::convert::Infallible;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::{self, AbortHandle, Abortable, Aborted, FutureExt as _};
+use futures::stream::{FuturesUnordered, StreamExt as _};
+
+use super::executor::{self, Executor, JoinError, JoinHandle};
+use super::{BlockingPool, TokioExecutor};
+
+/// Result of a computation running in the background. The computation starts right away, and so
+/// do the ones chained onto it, whether or not the promise is awaited.
+///
+/// Dropping a promise cancels it, so a `timeout` around a promise stops the work behind it too.
+/// Closures running on a thread can't be interrupted, and have to check the `CancellationToken`
+/// they are given instead.
+///
+/// Promises chained onto this one run on the same executor.
+pub struct Promise<T, E = Infallible> {
+    handle: JoinHandle<Result<Result<T, PromiseError<E>>, Aborted>>,
+    abort: AbortHandle,
+    token: CancellationToken,
+    executor: Arc<dyn Executor>,
+}
+
+/// Flag a computation polls to find out that its result is no longer wanted.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Why a `Promise` did not resolve to a value.
+pub enum PromiseError<E> {
+    /// The computation returned an error.
+    Rejected(E),
+    /// The computation panicked. The payload can be passed to `std::panic::resume_unwind`.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The task running the computation was cancelled, for example by a runtime shutdown.
+    Cancelled,
+}
+
+impl<E> PromiseError<E> {
+    pub fn map<E2, F: FnOnce(E) -> E2>(self, f: F) -> PromiseError<E2> {
+        match self {
+            PromiseError::Rejected(err) => PromiseError::Rejected(f(err)),
+            PromiseError::Panicked(payload) => PromiseError::Panicked(payload),
+            PromiseError::Cancelled => PromiseError::Cancelled,
+        }
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, PromiseError::Rejected(_))
+    }
+
+    pub fn is_panic(&self) -> bool {
+        matches!(self, PromiseError::Panicked(_))
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, PromiseError::Cancelled)
+    }
+}
+
+impl<E> From<JoinError> for PromiseError<E> {
+    fn from(err: JoinError) -> Self {
+        match err {
+            JoinError::Panicked(payload) => PromiseError::Panicked(payload),
+            JoinError::Cancelled => PromiseError::Cancelled,
+        }
+    }
+}
+
+impl<E: fmt::Debug> fmt::Debug for PromiseError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PromiseError::Rejected(err) => f.debug_tuple("Rejected").field(err).finish(),
+            PromiseError::Panicked(_) => f.write_str("Panicked(..)"),
+            PromiseError::Cancelled => f.write_str("Cancelled"),
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for PromiseError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PromiseError::Rejected(err) => err.fmt(f),
+            PromiseError::Panicked(_) => f.write_str("promise panicked"),
+            PromiseError::Cancelled => f.write_str("promise was cancelled"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> error::Error for PromiseError<E> {}
+
+impl<T: 'static + Send> Promise<T> {
+    /// Runs `resolve` in a new task. `resolve` should return early once the token is cancelled.
+    ///
+    /// `resolve` blocks an async worker thread while it runs, and a token is only cancelled by a
+    /// task that gets to run, e.g. the timer of a `timeout`. If every worker thread is busy, the
+    /// token is never cancelled, so long computations belong on `Promise::blocking`.
+    pub fn new<F>(resolve: F) -> Self
+    where
+        F: 'static + Send + FnOnce(&CancellationToken) -> T,
+    {
+        Promise::new_on(TokioExecutor, resolve)
+    }
+
+    /// Same as `Promise::new`, but runs the task on `executor` instead of on the current tokio
+    /// runtime.
+    pub fn new_on<X, F>(executor: X, resolve: F) -> Self
+    where
+        X: 'static + Executor,
+        F: 'static + Send + FnOnce(&CancellationToken) -> T,
+    {
+        Promise::spawn(
+            Arc::new(executor),
+            |token| async move { Ok(resolve(&token)) },
+        )
+    }
+
+    /// Same as `Promise::new`, but runs `resolve` on `pool` instead of on an async worker thread.
+    pub fn blocking<F>(pool: BlockingPool, resolve: F) -> Self
+    where
+        F: 'static + Send + FnOnce(&CancellationToken) -> T,
+    {
+        Promise::blocking_on(TokioExecutor, pool, resolve)
+    }
+
+    /// Same as `Promise::blocking`, but runs the task waiting for `pool` on `executor`.
+    pub fn blocking_on<X, F>(executor: X, pool: BlockingPool, resolve: F) -> Self
+    where
+        X: 'static + Executor,
+        F: 'static + Send + FnOnce(&CancellationToken) -> T,
+    {
+        Promise::spawn(Arc::new(executor), |token| {
+            pool.run(move || resolve(&token)).map(Ok)
+        })
+    }
+}
+
+impl<T: 'static + Send, E: 'static + Send> Promise<T, E> {
+    /// Same as `Promise::new`, but for a fallible computation. An error rejects the promise.
+    pub fn try_new<F>(resolve: F) -> Self
+    where
+        F: 'static + Send + FnOnce(&CancellationToken) -> Result<T, E>,
+    {
+        Promise::try_new_on(TokioExecutor, resolve)
+    }
+
+    /// Same as `Promise::try_new`, but runs the task on `executor`.
+    pub fn try_new_on<X, F>(executor: X, resolve: F) -> Self
+    where
+        X: 'static + Executor,
+        F: 'static + Send + FnOnce(&CancellationToken) -> Result<T, E>,
+    {
+        Promise::spawn(Arc::new(executor), |token| async move {
+            resolve(&token).map_err(PromiseError::Rejected)
+        })
+    }
+
+    fn spawn<F, Fut>(executor: Arc<dyn Executor>, f: F) -> Self
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: 'static + Future<Output = Result<T, PromiseError<E>>> + Send,
+    {
+        let token = CancellationToken::new();
+        let (abort, registration) = AbortHandle::new_pair();
+        let handle = executor::spawn(&*executor, Abortable::new(f(token.clone()), registration));
+        Promise {
+            handle,
+            abort,
+            token,
+            executor,
+        }
+    }
+
+    /// Executor for a promise that combines `promises`: the one of the first promise, if any.
+    fn executor_of(promises: &[Self]) -> Arc<dyn Executor> {
+        match promises.first() {
+            Some(promise) => Arc::clone(&promise.executor),
+            None => Arc::new(TokioExecutor),
+        }
+    }
+
+    /// Cancels the computation. The promise then resolves to `PromiseError::Cancelled`, unless
+    /// the computation already finished or ignores its token.
+    pub fn abort(&self) {
+        self.token.cancel();
+        self.abort.abort();
+    }
+
+    /// Transforms the value of this promise once it resolves.
+    pub fn map<U, F>(self, f: F) -> Promise<U, E>
+    where
+        U: 'static + Send,
+        F: 'static + Send + FnOnce(T) -> U,
+    {
+        Promise::spawn(
+            Arc::clone(&self.executor),
+            |_| async move { self.await.map(f) },
+        )
+    }
+
+    /// Transforms the error of this promise if it is rejected. Panics and cancellation are passed
+    /// on unchanged.
+    pub fn map_err<E2, F>(self, f: F) -> Promise<T, E2>
+    where
+        E2: 'static + Send,
+        F: 'static + Send + FnOnce(E) -> E2,
+    {
+        Promise::spawn(Arc::clone(&self.executor), |_| async move {
+            self.await.map_err(|err| err.map(f))
+        })
+    }
+
+    /// Starts the promise returned by `f` once this one resolves.
+    pub fn then<U, F>(self, f: F) -> Promise<U, E>
+    where
+        U: 'static + Send,
+        F: 'static + Send + FnOnce(T) -> Promise<U, E>,
+    {
+        Promise::spawn(Arc::clone(&self.executor), |_| async move {
+            f(self.await?).await
+        })
+    }
+
+    /// Calls `f` once this promise settles, whatever the outcome.
+    pub fn finally<F>(self, f: F) -> Self
+    where
+        F: 'static + Send + FnOnce(),
+    {
+        Promise::spawn(Arc::clone(&self.executor), |_| async move {
+            let result = self.await;
+            f();
+            result
+        })
+    }
+
+    /// Resolves to all values in order, or to the first error.
+    ///
+    /// Runs on the executor of the first promise, or on the current tokio runtime if there are
+    /// none. `Promise::all_on` picks the executor instead.
+    pub fn all<I>(promises: I) -> Promise<Vec<T>, E>
+    where
+        I: IntoIterator<Item = Self>,
+    {
+        let promises = promises.into_iter().collect::<Vec<_>>();
+        Promise::all_on(Promise::executor_of(&promises), promises)
+    }
+
+    /// Same as `Promise::all`, but runs on `executor`.
+    pub fn all_on<X, I>(executor: X, promises: I) -> Promise<Vec<T>, E>
+    where
+        X: 'static + Executor,
+        I: IntoIterator<Item = Self>,
+    {
+        let promises = promises.into_iter().collect::<Vec<_>>();
+        Promise::spawn(Arc::new(executor), |_| future::try_join_all(promises))
+    }
+
+    /// Settles like the first of `promises` to settle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `promises` is empty.
+    pub fn race<I>(promises: I) -> Self
+    where
+        I: IntoIterator<Item = Self>,
+    {
+        let promises = promises.into_iter().collect::<Vec<_>>();
+        assert!(!promises.is_empty(), "cannot race zero promises");
+        let executor = Promise::executor_of(&promises);
+        Promise::spawn(executor, |_| {
+            future::select_all(promises).map(|(result, ..)| result)
+        })
+    }
+
+    /// Resolves to the first value, or to all errors in order of settling if every promise fails.
+    ///
+    /// Runs where `Promise::all` runs; `Promise::any_on` picks the executor instead.
+    pub fn any<I>(promises: I) -> Promise<T, Vec<PromiseError<E>>>
+    where
+        I: IntoIterator<Item = Self>,
+    {
+        let promises = promises.into_iter().collect::<Vec<_>>();
+        Promise::any_on(Promise::executor_of(&promises), promises)
+    }
+
+    /// Same as `Promise::any`, but runs on `executor`.
+    pub fn any_on<X, I>(executor: X, promises: I) -> Promise<T, Vec<PromiseError<E>>>
+    where
+        X: 'static + Executor,
+        I: IntoIterator<Item = Self>,
+    {
+        let mut promises = promises.into_iter().collect::<FuturesUnordered<_>>();
+        Promise::spawn(Arc::new(executor), |_| async move {
+            let mut errors = Vec::new();
+            while let Some(result) = promises.next().await {
+                match result {
+                    Ok(value) => return Ok(value),
+                    Err(err) => errors.push(err),
+                }
+            }
+            Err(PromiseError::Rejected(errors))
+        })
+    }
+
+    /// Resolves to the outcome of every promise in order, once all of them have settled.
+    ///
+    /// Runs where `Promise::all` runs; `Promise::all_settled_on` picks the executor instead.
+    pub fn all_settled<I>(promises: I) -> Promise<Vec<Result<T, PromiseError<E>>>>
+    where
+        I: IntoIterator<Item = Self>,
+    {
+        let promises = promises.into_iter().collect::<Vec<_>>();
+        Promise::all_settled_on(Promise::executor_of(&promises), promises)
+    }
+
+    /// Same as `Promise::all_settled`, but runs on `executor`.
+    pub fn all_settled_on<X, I>(
+        executor: X,
+        promises: I,
+    ) -> Promise<Vec<Result<T, PromiseError<E>>>>
+    where
+        X: 'static + Executor,
+        I: IntoIterator<Item = Self>,
+    {
+        let promises = promises.into_iter().collect::<Vec<_>>();
+        Promise::spawn(Arc::new(executor), |_| future::join_all(promises).map(Ok))
+    }
+}
+
+impl<T, E> Future for Promise<T, E> {
+    type Output = Result<T, PromiseError<E>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.handle)
+            .poll(cx)
+            .map(|result| match result {
+                Ok(Ok(result)) => result,
+                Ok(Err(Aborted)) => Err(PromiseError::Cancelled),
+                Err(err) => Err(err.into()),
+            })
+    }
+}
+
+impl<T, E> Drop for Promise<T, E> {
+    fn drop(&mut self) {
+        // Also drops the promises this one was chained onto, which cancels them in turn.
+        self.token.cancel();
+        self.abort.abort();
+    }
+}
@@ -0,0 +1,77 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{BoxFuture, FutureExt as _};
+use tokio::time::{self, Duration, Instant};
+
+/// Token bucket shared by all clones of the handle, so several maps can stay under one quota.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    per_second: f64,
+    burst: f64,
+    /// May go negative, in which case it counts the tokens already promised to waiting callers.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Allows `per_second` items per second on average, and up to `burst` items at once.
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        assert!(per_second > 0.0, "rate must be positive");
+        let burst = f64::from(burst.max(1));
+        let bucket = Bucket {
+            per_second,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+        };
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(bucket)),
+        }
+    }
+
+    /// Waits until the next item may start.
+    pub async fn acquire(&self) {
+        let ready_at = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = (now - bucket.refilled_at).as_secs_f64() * bucket.per_second;
+            bucket.tokens = (bucket.tokens + refill).min(bucket.burst);
+            bucket.refilled_at = now;
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            now + Duration::from_secs_f64(-bucket.tokens / bucket.per_second)
+        };
+        time::delay_until(ready_at).await;
+    }
+
+    /// Wraps an async `op` so that every call waits for this limiter first. The result can be
+    /// passed to `parallel_map_async` or `try_parallel_map_async`.
+    pub fn limit<F, Fut, T>(
+        &self,
+        op: F,
+    ) -> impl Clone + Send + Sync + Fn(T) -> BoxFuture<'static, Fut::Output>
+    where
+        F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+        Fut: 'static + Future + Send,
+        T: 'static + Send,
+    {
+        let limiter = self.clone();
+        move |item| {
+            let limiter = limiter.clone();
+            let op = op.clone();
+            async move {
+                limiter.acquire().await;
+                op(item).await
+            }
+            .boxed()
+        }
+    }
+}
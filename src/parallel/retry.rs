@@ -0,0 +1,146 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt as _};
+use rand::{thread_rng, Rng as _};
+use tokio::time::{self, Duration};
+
+/// A result together with the number of times `op` was called to get it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retried<T> {
+    pub value: T,
+    pub attempts: u32,
+}
+
+/// How often and how fast to retry a failed item, for use with `try_parallel_map_async`.
+pub struct RetryPolicy<E> {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: f64,
+    retryable: Arc<dyn Fn(&E) -> bool + Send + Sync>,
+}
+
+impl<E> Clone for RetryPolicy<E> {
+    fn clone(&self) -> Self {
+        Self {
+            max_attempts: self.max_attempts,
+            initial_backoff: self.initial_backoff,
+            max_backoff: self.max_backoff,
+            multiplier: self.multiplier,
+            jitter: self.jitter,
+            retryable: Arc::clone(&self.retryable),
+        }
+    }
+}
+
+impl<E> fmt::Debug for RetryPolicy<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("multiplier", &self.multiplier)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+impl<E> RetryPolicy<E> {
+    /// Calls `op` at most `max_attempts` times per item, retrying every error, with a backoff
+    /// starting at 100 ms and doubling up to 10 secs.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+            retryable: Arc::new(|_| true),
+        }
+    }
+
+    pub fn initial_backoff(mut self, value: Duration) -> Self {
+        self.initial_backoff = value;
+        self
+    }
+
+    pub fn max_backoff(mut self, value: Duration) -> Self {
+        self.max_backoff = value;
+        self
+    }
+
+    pub fn multiplier(mut self, value: f64) -> Self {
+        self.multiplier = value;
+        self
+    }
+
+    /// Fraction of each backoff, between 0 and 1, that is randomly taken off so that failed
+    /// items don't all retry at the same moment.
+    pub fn jitter(mut self, value: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&value),
+            "jitter must be between 0 and 1"
+        );
+        self.jitter = value;
+        self
+    }
+
+    /// Only retries errors for which `predicate` returns true. Other errors fail the item right
+    /// away.
+    pub fn retry_if<P>(mut self, predicate: P) -> Self
+    where
+        P: 'static + Send + Sync + Fn(&E) -> bool,
+    {
+        self.retryable = Arc::new(predicate);
+        self
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry as i32);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+        let jitter = 1.0 - self.jitter * thread_rng().gen::<f64>();
+        Duration::from_secs_f64(backoff * jitter)
+    }
+}
+
+impl<E: 'static + Send> RetryPolicy<E> {
+    /// Wraps a fallible async `op` so that every item is retried according to this policy.
+    /// The result can be passed to `try_parallel_map_async`.
+    pub fn retry<F, Fut, T, U>(
+        &self,
+        op: F,
+    ) -> impl Clone + Send + Sync + Fn(T) -> BoxFuture<'static, Result<Retried<U>, Retried<E>>>
+    where
+        F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+        Fut: 'static + Future<Output = Result<U, E>> + Send,
+        T: 'static + Send + Clone,
+        U: 'static + Send,
+    {
+        let policy = self.clone();
+        move |item| {
+            let policy = policy.clone();
+            let op = op.clone();
+            async move {
+                let mut attempts = 0;
+                loop {
+                    attempts += 1;
+                    let err = match op(item.clone()).await {
+                        Ok(value) => return Ok(Retried { value, attempts }),
+                        Err(err) => err,
+                    };
+                    if attempts >= policy.max_attempts || !(policy.retryable)(&err) {
+                        return Err(Retried {
+                            value: err,
+                            attempts,
+                        });
+                    }
+                    time::delay_for(policy.backoff(attempts - 1)).await;
+                }
+            }
+            .boxed()
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, FutureExt as _};
+use futures::stream::{FuturesUnordered, StreamExt as _};
+use tokio::stream::Stream;
+use tokio::sync::mpsc;
+
+use super::ParallelConfig;
+
+/// Runs `f` and everything it spawns onto its `Scope`, and returns once all of it has finished.
+///
+/// Scoped tasks run concurrently on the task that awaits the scope instead of being spawned onto
+/// the runtime, so they may borrow anything that outlives the scope. That also means they share
+/// one thread: CPU-heavy work over borrowed data belongs in `parallel_map_scoped`.
+///
+/// Dropping the scope drops the tasks, and a panic in a task is resumed by the scope.
+pub async fn scope<'env, F, Fut>(f: F) -> Fut::Output
+where
+    F: FnOnce(Scope<'env>) -> Fut,
+    Fut: Future,
+{
+    let scope = Scope {
+        spawned: Arc::new(Mutex::new(Spawned::default())),
+    };
+    let mut body = Box::pin(f(scope.clone()));
+    let mut output = None;
+    let mut tasks = FuturesUnordered::new();
+    future::poll_fn(|cx| loop {
+        if output.is_none() {
+            if let Poll::Ready(value) = body.as_mut().poll(cx) {
+                output = Some(value);
+            }
+        }
+        let spawned = {
+            let mut spawned = scope.spawned.lock().unwrap();
+            spawned.waker = Some(cx.waker().clone());
+            mem::take(&mut spawned.tasks)
+        };
+        for task in spawned {
+            tasks.push(task);
+        }
+        while let Poll::Ready(Some(())) = tasks.poll_next_unpin(cx) {}
+        // Tasks may have spawned more tasks, which haven't been polled yet.
+        if !scope.spawned.lock().unwrap().tasks.is_empty() {
+            continue;
+        }
+        if tasks.is_empty() {
+            if let Some(value) = output.take() {
+                return Poll::Ready(value);
+            }
+        }
+        return Poll::Pending;
+    })
+    .await
+}
+
+/// Handle to spawn tasks that borrow data living for `'env`, see `scope`.
+#[derive(Clone)]
+pub struct Scope<'env> {
+    spawned: Arc<Mutex<Spawned<'env>>>,
+}
+
+/// Tasks spawned since the scope last polled its tasks.
+#[derive(Default)]
+struct Spawned<'env> {
+    tasks: Vec<BoxFuture<'env, ()>>,
+    waker: Option<Waker>,
+}
+
+impl<'env> Scope<'env> {
+    /// Runs `future` concurrently with the rest of the scope. The task runs to completion whether
+    /// or not its handle is awaited.
+    pub fn spawn<Fut>(&self, future: Fut) -> ScopedHandle<Fut::Output>
+    where
+        Fut: 'env + Future + Send,
+        Fut::Output: 'env + Send,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.spawn_task(future.map(|output| {
+            // Nobody wants the output if the handle is gone.
+            let _ = tx.send(output);
+        }));
+        ScopedHandle { rx }
+    }
+
+    /// Same as `parallel_map_async`, but `iter`, `op` and the results only have to outlive the
+    /// scope. Results are yielded as they finish.
+    ///
+    /// The workers run concurrently on the task awaiting the scope, not in parallel, so this only
+    /// pays off for an `op` that waits, e.g. on I/O. The executor of `config` is not used.
+    pub fn map_concurrent<I, F, Fut, T, U>(
+        &self,
+        iter: I,
+        op: F,
+        config: impl Into<ParallelConfig<U>>,
+    ) -> impl Stream<Item = U> + 'env
+    where
+        I: 'env + Iterator<Item = T> + Send,
+        F: 'env + Send + Sync + Fn(T) -> Fut,
+        Fut: 'env + Future<Output = U> + Send,
+        T: 'env + Send,
+        U: 'env + Send,
+    {
+        let config = config.into();
+        let budget = config.own_budget();
+        let (tx, rx) = mpsc::channel(budget.max_results());
+        let iter = Arc::new(Mutex::new(iter));
+        let op = Arc::new(op);
+        for _ in 0..config.workers() {
+            let iter = Arc::clone(&iter);
+            let op = Arc::clone(&op);
+            let budget = Arc::clone(&budget);
+            let mut tx = tx.clone();
+            self.spawn_task(async move {
+                loop {
+                    // Bound to a variable, so that the lock is released before awaiting.
+                    let item = iter.lock().unwrap().next();
+                    let item = match item {
+                        Some(item) => item,
+                        None => return,
+                    };
+                    let result = op(item).await;
+                    budget.acquire(budget.weigh(&result)).await;
+                    if tx.send(result).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        rx.map(move |result| {
+            budget.release(budget.weigh(&result));
+            result
+        })
+    }
+
+    fn spawn_task<Fut>(&self, task: Fut)
+    where
+        Fut: 'env + Future<Output = ()> + Send,
+    {
+        let mut spawned = self.spawned.lock().unwrap();
+        spawned.tasks.push(task.boxed());
+        if let Some(waker) = spawned.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves to the output of a task spawned onto a `Scope`.
+pub struct ScopedHandle<T> {
+    rx: oneshot::Receiver<T>,
+}
+
+impl<T> Future for ScopedHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        // The task is only dropped unfinished together with the scope.
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|output| output.expect("scoped task was dropped before it finished"))
+    }
+}
+
+/// Same as `parallel_map`, but `iter`, `op` and the results only have to outlive the call, and
+/// the results are returned in the order of `iter` once every item is done.
+///
+/// `op` runs on `n` tasks of the current rayon pool, the global one unless called in
+/// `ThreadPool::install`, and the calling thread blocks until they have finished. In async code,
+/// call this in `tokio::task::block_in_place`.
+pub fn parallel_map_scoped<I, F, T, U>(iter: I, op: F, n: usize) -> Vec<U>
+where
+    I: Iterator<Item = T> + Send,
+    F: Sync + Fn(T) -> U,
+    T: Send,
+    U: Send,
+{
+    assert!(n > 0, "a parallel map needs at least one worker");
+    let iter = Mutex::new(iter.enumerate());
+    let results = Mutex::new(Vec::new());
+    rayon::scope(|s| {
+        for _ in 0..n {
+            s.spawn(|_| loop {
+                // Bound to a variable, so that the lock is released before running `op`.
+                let item = iter.lock().unwrap().next();
+                let (i, item) = match item {
+                    Some(item) => item,
+                    None => return,
+                };
+                let result = op(item);
+                results.lock().unwrap().push((i, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_unstable_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
@@ -0,0 +1,110 @@
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::stream;
+use tokio::time::{self, Instant};
+
+use super::{config, spawn_workers, ParallelConfig, ParallelIterator, WorkerConfig};
+
+/// Time limits for `parallel_map_timeout`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeLimits {
+    /// Maximum time `op` may take for a single item.
+    pub item: Option<Duration>,
+    /// Point in time after which no more items are started and items in flight are cut off.
+    pub deadline: Option<Instant>,
+}
+
+impl TimeLimits {
+    fn until(&self, started: Instant) -> Option<Instant> {
+        match (self.item.map(|item| started + item), self.deadline) {
+            (Some(item), Some(deadline)) => Some(if item < deadline { item } else { deadline }),
+            (item, deadline) => item.or(deadline),
+        }
+    }
+}
+
+/// Why an item of `parallel_map_timeout` has no result. `index` is the position of the item in
+/// the input.
+#[derive(Debug)]
+pub enum TimeLimitError<T> {
+    /// `op` was started but did not finish in time.
+    TimedOut { index: usize, elapsed: Duration },
+    /// The deadline passed, or the map was cancelled, before the item was started.
+    Skipped { index: usize, item: T },
+}
+
+impl<T> fmt::Display for TimeLimitError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeLimitError::TimedOut { index, elapsed } => write!(
+                f,
+                "item {} timed out after {} secs",
+                index,
+                elapsed.as_secs_f64()
+            ),
+            TimeLimitError::Skipped { index, .. } => {
+                write!(f, "item {} was skipped before it started", index)
+            }
+        }
+    }
+}
+
+impl<T: fmt::Debug> error::Error for TimeLimitError<T> {}
+
+/// Same as `parallel_map_async`, but gives up on an item once `limits` are exceeded.
+///
+/// Every item pulled from the input still produces exactly one result: items that run out of time
+/// yield `TimedOut`, and items pulled after the deadline are handed back as `Skipped` without
+/// running `op`. So are the items that workers took but hadn't started when the map was cancelled.
+/// Only finished items are weighed against `config`.
+pub fn parallel_map_timeout<I, F, Fut, T, U>(
+    iter: I,
+    op: F,
+    config: impl Into<ParallelConfig<U>>,
+    limits: TimeLimits,
+) -> ParallelIterator<Result<U, TimeLimitError<T>>>
+where
+    I: 'static + Iterator<Item = T> + Send,
+    F: 'static + Send + Sync + Clone + Fn(T) -> Fut,
+    Fut: Future<Output = U> + Send,
+    T: 'static + Send,
+    U: 'static + Send,
+{
+    let config = WorkerConfig {
+        is_failure: Result::is_err,
+        finish_chunk: true,
+        ..WorkerConfig::new(config.into(), config::ok)
+    };
+    let stop = Arc::clone(&config.stop);
+    spawn_workers(
+        stream::iter(iter.enumerate()),
+        move |(index, item)| {
+            let op = op.clone();
+            let cancelled = stop.load(Ordering::SeqCst);
+            async move {
+                let started = Instant::now();
+                let too_late = match limits.deadline {
+                    Some(deadline) => started >= deadline,
+                    None => false,
+                };
+                if cancelled || too_late {
+                    return Err(TimeLimitError::Skipped { index, item });
+                }
+                let result = match limits.until(started) {
+                    Some(until) => time::timeout_at(until, op(item)).await.ok(),
+                    None => Some(op(item).await),
+                };
+                result.ok_or_else(|| TimeLimitError::TimedOut {
+                    index,
+                    elapsed: started.elapsed(),
+                })
+            }
+        },
+        config,
+    )
+}
@@ -0,0 +1,278 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use futures::future;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::process::{ChildStdin, Command};
+use tokio::time;
+
+use crate::Result;
+
+/// How long the pipes of a killed process are still read from.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Where a child process reads its stdin from.
+#[derive(Debug, Clone)]
+pub enum Input {
+    /// Reads nothing, as if stdin were `/dev/null`.
+    Null,
+    /// Shares the stdin of this process.
+    Inherit,
+    /// Reads these bytes, then end of file.
+    Bytes(Vec<u8>),
+    /// Reads the contents of this file.
+    File(PathBuf),
+}
+
+/// What happens to a stdout or stderr of a child process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Collected into the `ProcessOutcome`.
+    Capture,
+    /// Shares the stream of this process.
+    Inherit,
+    /// Thrown away.
+    Discard,
+}
+
+impl Output {
+    fn stdio(self) -> Stdio {
+        match self {
+            Output::Capture => Stdio::piped(),
+            Output::Inherit => Stdio::inherit(),
+            Output::Discard => Stdio::null(),
+        }
+    }
+}
+
+/// Description of a child process to run. Running the same spec again starts a new process.
+#[derive(Debug, Clone)]
+pub struct ProcessSpec {
+    program: OsString,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    env_clear: bool,
+    cwd: Option<PathBuf>,
+    stdin: Input,
+    stdout: Output,
+    stderr: Output,
+    timeout: Option<Duration>,
+}
+
+/// How a child process ended.
+#[derive(Debug)]
+pub struct ProcessOutcome {
+    pub status: ExitStatus,
+    /// Empty unless stdout was captured.
+    pub stdout: Vec<u8>,
+    /// Empty unless stderr was captured.
+    pub stderr: Vec<u8>,
+    /// Time from starting the process until it exited.
+    pub elapsed: Duration,
+    /// Whether the process was killed for running out of time. The outputs then hold what the
+    /// process wrote until it was killed.
+    pub killed: bool,
+}
+
+impl ProcessOutcome {
+    /// Whether the process exited on its own with status 0.
+    pub fn success(&self) -> bool {
+        !self.killed && self.status.success()
+    }
+}
+
+impl ProcessSpec {
+    /// Runs `program` with no arguments, no stdin and both outputs captured.
+    pub fn new(program: impl AsRef<OsStr>) -> Self {
+        ProcessSpec {
+            program: program.as_ref().to_owned(),
+            args: Vec::new(),
+            env: Vec::new(),
+            env_clear: false,
+            cwd: None,
+            stdin: Input::Null,
+            stdout: Output::Capture,
+            stderr: Output::Capture,
+            timeout: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Sets an environment variable on top of the inherited environment.
+    pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> Self {
+        self.env
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
+    }
+
+    /// Starts from an empty environment instead of inheriting this one.
+    pub fn env_clear(mut self) -> Self {
+        self.env_clear = true;
+        self
+    }
+
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cwd = Some(dir.into());
+        self
+    }
+
+    pub fn stdin(mut self, input: Input) -> Self {
+        self.stdin = input;
+        self
+    }
+
+    pub fn stdout(mut self, output: Output) -> Self {
+        self.stdout = output;
+        self
+    }
+
+    pub fn stderr(mut self, output: Output) -> Self {
+        self.stderr = output;
+        self
+    }
+
+    /// Kills the process if it is still running after `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Starts the process and waits until it exits or runs out of time.
+    ///
+    /// Dropping the returned future kills the process.
+    pub async fn run(&self) -> Result<ProcessOutcome> {
+        let mut command = self.command()?;
+        let started = Instant::now();
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("{:?} failed to start", self.program))?;
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take();
+        let mut stderr = child.stderr.take();
+        let mut stdout_buf = Vec::new();
+        let mut stderr_buf = Vec::new();
+        let input = match &self.stdin {
+            Input::Bytes(bytes) => &bytes[..],
+            _ => &[],
+        };
+        // All at once, so that a child that blocks on a full pipe doesn't block us in turn.
+        let run = future::try_join4(
+            write_input(stdin, input),
+            read_output(stdout.as_mut(), &mut stdout_buf),
+            read_output(stderr.as_mut(), &mut stderr_buf),
+            &mut child,
+        );
+        let result = match self.timeout {
+            Some(timeout) => time::timeout(timeout, run).await.ok(),
+            None => Some(run.await),
+        };
+        let killed = result.is_none();
+        let (status, elapsed) = match result {
+            Some(result) => {
+                let ((), (), (), status) = result.context("Command failed to run")?;
+                (status, started.elapsed())
+            }
+            None => {
+                // The process may have exited in the meantime, so failing to kill it is fine.
+                let _ = child.kill();
+                let status = (&mut child).await.context("Command failed to run")?;
+                let elapsed = started.elapsed();
+                // Output written right before the kill may still be in the pipes. They are only
+                // closed once the children of the process exit too, so don't wait for that.
+                let drain = future::try_join(
+                    read_output(stdout.as_mut(), &mut stdout_buf),
+                    read_output(stderr.as_mut(), &mut stderr_buf),
+                );
+                let _ = time::timeout(DRAIN_TIMEOUT, drain).await;
+                (status, elapsed)
+            }
+        };
+        Ok(ProcessOutcome {
+            status,
+            stdout: stdout_buf,
+            stderr: stderr_buf,
+            elapsed,
+            killed,
+        })
+    }
+
+    fn command(&self) -> Result<Command> {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        if self.env_clear {
+            command.env_clear();
+        }
+        for (key, value) in &self.env {
+            command.env(key, value);
+        }
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        let stdin = match &self.stdin {
+            Input::Null => Stdio::null(),
+            Input::Inherit => Stdio::inherit(),
+            Input::Bytes(_) => Stdio::piped(),
+            Input::File(path) => File::open(path)
+                .with_context(|| format!("Could not open {:?} for stdin", path))?
+                .into(),
+        };
+        command
+            .stdin(stdin)
+            .stdout(self.stdout.stdio())
+            .stderr(self.stderr.stdio())
+            .kill_on_drop(true);
+        Ok(command)
+    }
+}
+
+/// Writes `input` to the child and closes its stdin.
+async fn write_input(stdin: Option<ChildStdin>, input: &[u8]) -> io::Result<()> {
+    if let Some(mut stdin) = stdin {
+        match stdin.write_all(input).await {
+            // The child is free to exit without reading all of its input.
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Appends the output of the child to `buf` until the end of the stream.
+///
+/// Everything read so far stays in `buf` when the future is dropped, so that a timeout doesn't
+/// lose it.
+async fn read_output<R>(output: Option<&mut R>, buf: &mut Vec<u8>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    if let Some(output) = output {
+        let mut chunk = [0; 8192];
+        loop {
+            let len = output.read(&mut chunk).await?;
+            if len == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+    Ok(())
+}